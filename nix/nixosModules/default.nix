@@ -10,18 +10,66 @@
     in {
       options.eldolfin.services.codlab-server = {
         enable = mkEnableOption "Enables the codlab-server service";
-        # TODO: allow server port to be configurable
         port = mkOption {
           type = types.port;
           default = 7575;
           example = 7575;
-          description = "The server port to open (firewall will be opened)";
+          description = "The server port to open (firewall will be opened), configFile can set another one to listen on";
+        };
+        bind = mkOption {
+          type = types.nullOr types.str;
+          default = null;
+          example = "127.0.0.1";
+          description = "The address the server listens on, null to take it from configFile or default to 0.0.0.0";
+        };
+        logLevel = mkOption {
+          type = types.nullOr (types.enum ["error" "warn" "info" "debug" "trace"]);
+          default = null;
+          example = "info";
+          description = "Maximum level of the server logs, null to take it from configFile or default to debug";
+        };
+        storage = mkOption {
+          type = types.nullOr types.str;
+          default = "/var/lib/eldolfin.codlab-server/sessions";
+          description = "Directory where the sessions are saved to survive restarts, null to only keep them in memory";
+        };
+        recordFile = mkOption {
+          type = types.nullOr types.str;
+          default = null;
+          example = "/var/lib/eldolfin.codlab-server/changes.jsonl";
+          description = "File where every change relayed is appended, to be replayed with codlab-replay";
+        };
+        configFile = mkOption {
+          type = types.nullOr types.str;
+          default = null;
+          example = "/run/secrets/codlab-server.toml";
+          description = "TOML config file of the server, to give it access tokens without copying them to the Nix store";
+        };
+        tls = {
+          cert = mkOption {
+            type = types.nullOr types.path;
+            default = null;
+            example = "/var/lib/acme/codlab.example.com/fullchain.pem";
+            description = "Certificate chain (PEM) to serve wss:// instead of ws://";
+          };
+          key = mkOption {
+            type = types.nullOr types.str;
+            default = null;
+            example = "/var/lib/acme/codlab.example.com/key.pem";
+            description = "Private key (PEM) of the certificate, read at runtime so that it is not copied to the Nix store";
+          };
         };
       };
 
       config =
         mkIf cfg.enable
         {
+          assertions = [
+            {
+              assertion = (cfg.tls.cert == null) == (cfg.tls.key == null);
+              message = "eldolfin.services.codlab-server.tls.cert and tls.key must be set together";
+            }
+          ];
           networking = {
             firewall.allowedTCPPorts = [cfg.port];
           };
@@ -36,7 +84,15 @@
             in {
               Restart = "always";
               RestartSec = 2;
-              ExecStart = "!${pkg}/bin/server";
+              ExecStart = let
+                portArgs = optionalString (cfg.configFile == null) " --port ${toString cfg.port}";
+                bindArgs = optionalString (cfg.bind != null) " --bind ${cfg.bind}";
+                logArgs = optionalString (cfg.logLevel != null) " --log-level ${cfg.logLevel}";
+                tlsArgs = optionalString (cfg.tls.cert != null) " --tls-cert ${cfg.tls.cert} --tls-key ${cfg.tls.key}";
+                storageArgs = optionalString (cfg.storage != null) " --storage ${cfg.storage}";
+                recordArgs = optionalString (cfg.recordFile != null) " --record ${cfg.recordFile}";
+                configArgs = optionalString (cfg.configFile != null) " --config ${cfg.configFile}";
+              in "!${pkg}/bin/server${portArgs}${bindArgs}${logArgs}${tlsArgs}${storageArgs}${recordArgs}${configArgs}";
               RuntimeDirectory = "eldolfin.codlab-server";
               RuntimeDirectoryMode = "0755";
               StateDirectory = "eldolfin.codlab-server";
@@ -1,12 +1,18 @@
-use anyhow::{Context, anyhow};
+use anyhow::{Context, anyhow, bail};
 use async_lsp::{
-    ClientSocket, LanguageClient, LanguageServer, ResponseError,
+    ClientSocket, ErrorCode, LanguageClient, LanguageServer, ResponseError,
     client_monitor::ClientProcessMonitorLayer,
     concurrency::ConcurrencyLayer,
     lsp_types::{
-        DidChangeConfigurationParams, DidChangeTextDocumentParams, DidOpenTextDocumentParams,
-        InitializeParams, InitializeResult, ServerCapabilities, TextDocumentContentChangeEvent,
-        TextDocumentSyncCapability::Kind, TextDocumentSyncKind,
+        ApplyWorkspaceEditParams, CodeActionParams, CodeActionProviderCapability,
+        CodeActionResponse, CreateFile, CreateFileOptions, DidChangeConfigurationParams,
+        DidChangeTextDocumentParams, DidCloseTextDocumentParams, DidOpenTextDocumentParams,
+        DidSaveTextDocumentParams, DocumentChangeOperation, DocumentChanges, DocumentHighlight,
+        DocumentHighlightParams, ExecuteCommandOptions, ExecuteCommandParams, InitializeParams,
+        InitializeResult, InitializedParams, MessageType, OneOf, Position, Range, ResourceOp,
+        ServerCapabilities, ShowDocumentParams, ShowMessageParams, TextDocumentContentChangeEvent,
+        TextDocumentSyncCapability, TextDocumentSyncKind, TextDocumentSyncOptions,
+        TextDocumentSyncSaveOptions, Url, VersionedTextDocumentIdentifier, WorkspaceEdit,
     },
     panic::CatchUnwindLayer,
     router::Router,
@@ -15,37 +21,680 @@ use async_lsp::{
 };
 use clap::Parser;
 use codlab::{
-    change::{self, ChangeEvent},
+    buffer::Buffer,
     change_event_to_workspace_edit,
     common::init_logger,
-    messages::{Change, ClientMessage, CommonMessage, ServerMessage},
-    peekable_channel::PeekableReceiver,
+    crdt, diff,
+    echo::{Echo, EchoFilter},
+    encoding::{self, PositionEncoding},
+    history::HistoryNotification,
+    messages::{
+        Change, Checksum, ClientMessage, CommonMessage, CrdtChange, DocumentSavedNotification,
+        Peer, PeersNotification, PeersParams, Presence, Revision, Role, ServerMessage, Snapshot,
+        SyncEngine,
+    },
+    ot,
+    presence::{
+        PresenceNotification, Presences, UserLeftNotification, UserLeftParams, map_positions,
+    },
+    version::{Ordered, VersionTracker},
+    workspace::Workspace,
 };
-use futures::{SinkExt, StreamExt as _, TryStreamExt, future::BoxFuture, stream::SplitSink};
+use futures::{
+    SinkExt, StreamExt as _, TryStreamExt,
+    future::BoxFuture,
+    stream::{SplitSink, SplitStream},
+};
+use serde::Deserialize;
 use std::{
+    collections::{HashMap, HashSet},
     ops::ControlFlow,
-    sync::{
-        Arc,
-        mpsc::{self, Sender},
-    },
+    sync::Arc,
+    time::Duration,
 };
 use tokio::sync::Mutex;
-use tokio_tungstenite::{WebSocketStream, connect_async};
+use tokio_tungstenite::{
+    WebSocketStream, connect_async_with_config,
+    tungstenite::{
+        Message, client::IntoClientRequest as _, http::header::AUTHORIZATION, protocol::CloseFrame,
+    },
+};
 use tower::ServiceBuilder;
-use tracing::{debug, info};
+use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
-type CodelabServer = SplitSink<
-    WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>,
-    tokio_tungstenite::tungstenite::Message,
->;
+type WebSocket = WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+type CodelabServer = SplitSink<WebSocket, Message>;
+/// Set once connected to the server, in `initialize`
+type ServerConnection = Arc<Mutex<Option<Connection>>>;
+type SharedDocuments = Arc<std::sync::Mutex<Documents>>;
+
+/// How to connect to the server, resolved in `initialize`
+#[derive(Debug, Default, Clone)]
+struct Login {
+    server_addr: String,
+    token: Option<String>,
+    username: Option<String>,
+    role: Role,
+    workspace: Workspace,
+}
+
+struct Connection {
+    sink: CodelabServer,
+    /// Maps the URIs of the messages sent to the server, relative to the workspace
+    workspace: Workspace,
+}
+
+enum SyncDocument {
+    Ot(ot::ClientDocument),
+    Crdt(Box<crdt::Document>),
+}
+
+struct SharedDocument {
+    sync: SyncDocument,
+    /// Version of the document in the editor, as of the last change it sent
+    version: i32,
+}
+
+impl SharedDocument {
+    /// An empty document, its content is given by the changes of the server
+    fn new(engine: SyncEngine, version: i32) -> Self {
+        let sync = match engine {
+            SyncEngine::Ot => SyncDocument::Ot(ot::ClientDocument::new(String::new())),
+            SyncEngine::Crdt => SyncDocument::Crdt(Box::default()),
+        };
+        Self { sync, version }
+    }
+
+    /// Content of the document as seen by the editor
+    fn text(&self) -> String {
+        match &self.sync {
+            SyncDocument::Ot(ot) => ot.text().to_owned(),
+            SyncDocument::Crdt(crdt) => crdt.text(),
+        }
+    }
+}
+
+/// Documents shared with the server
+#[derive(Default)]
+struct Documents {
+    engine: SyncEngine,
+    by_uri: HashMap<Url, SharedDocument>,
+    /// Documents waiting to be restored from a snapshot
+    desynced: HashSet<Url>,
+    /// Documents shared by this client which the server did not acknowledge yet
+    opening: HashSet<Url>,
+    /// Content in the editor of the documents of the previous session, replaced by the snapshot
+    /// of the new one
+    left: HashMap<Url, String>,
+    /// Cursors and selections of the peers
+    presences: Presences,
+    /// Encoding of the positions exchanged with the editor
+    encoding: PositionEncoding,
+    /// Content of the documents open in the editor, as of the last change it sent, which the
+    /// positions it sends refer to
+    editors: HashMap<Url, Buffer>,
+    /// Whether to save the documents saved by the peers
+    save_with_peers: bool,
+    /// Workspace of the editor, the only files read and written to
+    workspace: Workspace,
+}
+
+impl Documents {
+    fn set_engine(&mut self, engine: SyncEngine) {
+        if self.engine != engine {
+            self.engine = engine;
+            self.by_uri.clear();
+            self.left.clear();
+            self.presences.clear();
+        }
+    }
+
+    /// Records a document opened in the editor.
+    /// Returns the message telling the server, which shares the document with the peers if
+    /// they don't have it yet and `share` is true, and the change to apply in the editor if the
+    /// document is shared with another content.
+    fn open(
+        &mut self,
+        uri: Url,
+        text: String,
+        version: i32,
+        share: bool,
+    ) -> anyhow::Result<(ClientMessage, Option<Echo>)> {
+        self.editors.insert(uri.clone(), Buffer::new(&text));
+        if let Some(document) = self.by_uri.get_mut(&uri) {
+            // already known from a snapshot or from remote changes
+            document.version = version;
+            let shared = document.text();
+            let msg = ClientMessage::OpenDocument {
+                uri: uri.clone(),
+                content: None,
+            };
+            // the file may be outdated, unless the document is about to be restored
+            if shared == text || self.left.contains_key(&uri) || self.desynced.contains(&uri) {
+                return Ok((msg, None));
+            }
+            let echo = Echo {
+                change: DidChangeTextDocumentParams {
+                    text_document: VersionedTextDocumentIdentifier::new(uri, version),
+                    content_changes: self.to_editor(&text, &diff::changes(&text, &shared)),
+                },
+                text: shared,
+                author: None,
+            };
+            return Ok((msg, Some(echo)));
+        }
+        if !share {
+            return Ok((ClientMessage::OpenDocument { uri, content: None }, None));
+        }
+        let engine = self.engine;
+        self.by_uri
+            .insert(uri.clone(), SharedDocument::new(engine, version));
+        self.opening.insert(uri.clone());
+        // the content is the first change made to the document
+        let msg = self.local_change(DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier::new(uri.clone(), version),
+            content_changes: vec![TextDocumentContentChangeEvent {
+                range: Some(Range::default()),
+                range_length: None,
+                text,
+            }],
+        })?;
+        let content = match msg {
+            Some(ClientMessage::Common(change)) => Some(change),
+            _ => None,
+        };
+        Ok((ClientMessage::OpenDocument { uri, content }, None))
+    }
+
+    /// Forgets the content of a document closed in the editor, it stays in sync with the peers
+    fn close(&mut self, uri: &Url) {
+        self.editors.remove(uri);
+    }
+
+    /// Writes the content of a document saved by a peer to its file, if enabled and if it is a
+    /// file of the workspace
+    fn save(&self, uri: &Url) -> anyhow::Result<()> {
+        if !self.save_with_peers {
+            return Ok(());
+        }
+        let Some(document) = self.by_uri.get(uri) else {
+            return Ok(());
+        };
+        let path = self
+            .workspace
+            .file_path(uri)
+            .ok_or_else(|| anyhow!("{uri} is not a file of the workspace"))?;
+        std::fs::write(&path, document.text())
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// Forgets the documents of the previous session, keeping their version in the editor
+    fn clear(&mut self) {
+        let engine = self.engine;
+        for (uri, document) in &mut self.by_uri {
+            self.left.insert(uri.clone(), document.text());
+            *document = SharedDocument::new(engine, document.version);
+        }
+        self.desynced.clear();
+        self.opening.clear();
+        self.presences.clear();
+    }
+
+    /// Marks a document as out of sync, so that it is restored from the next snapshot
+    fn desync(&mut self, uri: Url) {
+        self.desynced.insert(uri);
+    }
+
+    /// Replaces a document with its content on the server.
+    /// Returns the change to apply in the editor, if it was not up to date.
+    fn restore(&mut self, snapshot: Snapshot) -> anyhow::Result<Option<Echo>> {
+        // a peer shared the document first, its content replaces the local one
+        let opening = self.opening.remove(&snapshot.uri);
+        // changes broadcasted before the snapshot was taken may already have been applied
+        if !self.desynced.remove(&snapshot.uri)
+            && let Some(SharedDocument {
+                sync: SyncDocument::Ot(ot),
+                ..
+            }) = self.by_uri.get(&snapshot.uri)
+            && ot.revision() >= snapshot.revision
+        {
+            return Ok(None);
+        }
+        let sync = match self.engine {
+            SyncEngine::Ot => SyncDocument::Ot(ot::ClientDocument::at_revision(
+                snapshot.text.clone(),
+                snapshot.revision,
+            )),
+            SyncEngine::Crdt => {
+                let Some(data) = snapshot.crdt else {
+                    bail!("received a snapshot without its crdt document");
+                };
+                let mut crdt = crdt::Document::load(&data)?;
+                // keep the local changes the server did not receive yet
+                if !opening
+                    && let Some(SharedDocument {
+                        sync: SyncDocument::Crdt(local),
+                        ..
+                    }) = self.by_uri.get_mut(&snapshot.uri)
+                {
+                    crdt.merge(local)?;
+                }
+                SyncDocument::Crdt(Box::new(crdt))
+            }
+        };
+        let text = match &sync {
+            SyncDocument::Ot(_) => snapshot.text,
+            SyncDocument::Crdt(crdt) => crdt.text(),
+        };
+        let version = self
+            .by_uri
+            .get(&snapshot.uri)
+            .map_or(0, |document| document.version);
+        let editor_text = self.editor_text(&snapshot.uri);
+        self.by_uri
+            .insert(snapshot.uri.clone(), SharedDocument { sync, version });
+        if editor_text == text {
+            return Ok(None);
+        }
+        let content_changes = self.to_editor(&editor_text, &diff::changes(&editor_text, &text));
+        Ok(Some(Echo {
+            change: DidChangeTextDocumentParams {
+                text_document: VersionedTextDocumentIdentifier::new(snapshot.uri, version),
+                content_changes,
+            },
+            text,
+            author: None,
+        }))
+    }
+
+    /// Content of a document in the editor, as far as the client knows.
+    /// Edits to documents which are not open apply to the file, if it is a file of the workspace.
+    fn editor_text(&mut self, uri: &Url) -> String {
+        if let Some(text) = self.left.remove(uri) {
+            return text;
+        }
+        if let Some(document) = self.by_uri.get(uri) {
+            return document.text();
+        }
+        match self.editors.get(uri) {
+            Some(editor) => editor.to_string(),
+            None => self
+                .workspace
+                .file_path(uri)
+                .and_then(|path| std::fs::read_to_string(path).ok())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Converts the positions of changes sent by the editor to chars, keeping track of the
+    /// content of the document in the editor
+    fn editor_change(
+        &mut self,
+        uri: &Url,
+        changes: &[TextDocumentContentChangeEvent],
+    ) -> Vec<TextDocumentContentChangeEvent> {
+        if !self.editors.contains_key(uri) {
+            let text = self
+                .by_uri
+                .get(uri)
+                .map(SharedDocument::text)
+                .unwrap_or_default();
+            self.editors.insert(uri.clone(), Buffer::new(&text));
+        }
+        let editor = self.editors.get_mut(uri).expect("editor to be inserted");
+        encoding::convert_changes(editor, changes, self.encoding, PositionEncoding::WIRE)
+    }
+
+    /// Converts the positions of changes to the encoding of the editor, `before` being the
+    /// content of the document they apply to
+    fn to_editor(
+        &self,
+        before: &str,
+        changes: &[TextDocumentContentChangeEvent],
+    ) -> Vec<TextDocumentContentChangeEvent> {
+        if self.encoding == PositionEncoding::WIRE {
+            return changes.to_vec();
+        }
+        encoding::convert_changes(
+            &mut Buffer::new(before),
+            changes,
+            PositionEncoding::WIRE,
+            self.encoding,
+        )
+    }
+
+    /// Converts positions in a document, as known by the client, to the encoding of the editor
+    fn positions_to_editor(&self, uri: &Url, positions: &[Position]) -> Vec<Position> {
+        if self.encoding == PositionEncoding::WIRE {
+            return positions.to_vec();
+        }
+        let text = self
+            .by_uri
+            .get(uri)
+            .map(SharedDocument::text)
+            .unwrap_or_default();
+        let buffer = Buffer::new(&text);
+        positions
+            .iter()
+            .map(|position| {
+                buffer.convert_position(*position, PositionEncoding::WIRE, self.encoding)
+            })
+            .collect()
+    }
+
+    /// Cursors and selections of the peers in a document, to show in the editor
+    fn highlights(&self, uri: &Url) -> Vec<DocumentHighlight> {
+        let mut highlights = self.presences.highlights(uri);
+        let positions: Vec<_> = highlights
+            .iter()
+            .flat_map(|highlight| [highlight.range.start, highlight.range.end])
+            .collect();
+        let positions = self.positions_to_editor(uri, &positions);
+        for (highlight, range) in highlights.iter_mut().zip(positions.chunks(2)) {
+            highlight.range = Range::new(range[0], range[1]);
+        }
+        highlights
+    }
+
+    /// Checksums of the documents as last seen by the server
+    fn checksums(&mut self) -> Vec<Checksum> {
+        self.by_uri
+            .iter_mut()
+            .map(|(uri, document)| match &mut document.sync {
+                SyncDocument::Ot(ot) => Checksum {
+                    uri: uri.clone(),
+                    revision: ot.revision(),
+                    crdt_heads: None,
+                    hash: Checksum::hash(ot.server_text()),
+                },
+                SyncDocument::Crdt(crdt) => Checksum {
+                    uri: uri.clone(),
+                    revision: 0,
+                    crdt_heads: Some(crdt.heads()),
+                    hash: Checksum::hash(&crdt.text()),
+                },
+            })
+            .collect()
+    }
+
+    fn get_or_create(&mut self, uri: Url) -> &mut SharedDocument {
+        let engine = self.engine;
+        self.by_uri
+            .entry(uri)
+            .or_insert_with(|| SharedDocument::new(engine, 0))
+    }
+
+    /// Records changes made in the editor.
+    /// Returns the message to send to the server, if any.
+    fn local_change(
+        &mut self,
+        params: DidChangeTextDocumentParams,
+    ) -> anyhow::Result<Option<ClientMessage>> {
+        let uri = params.text_document.uri.clone();
+        let document = self.get_or_create(uri.clone());
+        document.version = params.text_document.version;
+        if params.content_changes.is_empty() {
+            // only echoed remote changes
+            return Ok(None);
+        }
+        let before = document.text();
+        // peers only receive the ranges which changed
+        let content_changes = diff::ranged_changes(&before, params.content_changes);
+        if content_changes.is_empty() {
+            // the editor sent its content unchanged
+            return Ok(None);
+        }
+        let msg = match &mut document.sync {
+            SyncDocument::Ot(ot) => ot.local_change(&content_changes)?.map(|content_changes| {
+                change_message(params.text_document, ot.revision(), content_changes)
+            }),
+            SyncDocument::Crdt(crdt) => Some(ClientMessage::Common(CommonMessage::CrdtChange(
+                CrdtChange {
+                    id: Uuid::new_v4(),
+                    author: None,
+                    changes: crdt.local_change(&content_changes)?,
+                    uri: params.text_document.uri,
+                },
+            ))),
+        };
+        self.move_presences(&uri, &before, &content_changes);
+        Ok(msg)
+    }
+
+    /// Moves the cursors of the peers through changes made to a document, whose content was
+    /// `before`
+    fn move_presences(
+        &mut self,
+        uri: &Url,
+        before: &str,
+        changes: &[TextDocumentContentChangeEvent],
+    ) {
+        if let Err(err) = self.presences.transform(uri, before, changes) {
+            warn!("Failed to move the cursors of the peers in {uri}: {err:#}");
+        }
+    }
+
+    /// Converts a presence in the editor to the message to send to the server
+    fn local_presence(&self, mut presence: Presence) -> anyhow::Result<ClientMessage> {
+        if let Some(editor) = self.editors.get(&presence.uri) {
+            map_positions(&mut presence, |positions| {
+                Ok(positions
+                    .iter()
+                    .map(|position| {
+                        editor.convert_position(*position, self.encoding, PositionEncoding::WIRE)
+                    })
+                    .collect())
+            })?;
+        }
+        if let Some(SharedDocument {
+            sync: SyncDocument::Ot(ot),
+            ..
+        }) = self.by_uri.get(&presence.uri)
+        {
+            map_positions(&mut presence, |positions| ot.server_positions(positions))?;
+            presence.revision = ot.revision();
+        }
+        Ok(ClientMessage::Presence(presence))
+    }
+
+    /// Records the presence of a peer.
+    /// Returns it with positions in the document as seen by the editor.
+    fn remote_presence(&mut self, mut presence: Presence) -> anyhow::Result<Presence> {
+        if let Some(SharedDocument {
+            sync: SyncDocument::Ot(ot),
+            ..
+        }) = self.by_uri.get(&presence.uri)
+        {
+            if presence.revision != ot.revision() {
+                bail!(
+                    "the presence is at revision {} but the document is at revision {}",
+                    presence.revision,
+                    ot.revision()
+                );
+            }
+            map_positions(&mut presence, |positions| ot.local_positions(positions))?;
+        }
+        self.presences.update(presence.clone());
+        let uri = presence.uri.clone();
+        map_positions(&mut presence, |positions| {
+            Ok(self.positions_to_editor(&uri, positions))
+        })?;
+        Ok(presence)
+    }
+
+    /// Presence of the followed peer, with positions in the document as seen by the editor
+    fn followed(&self) -> Option<Presence> {
+        let mut presence = self.presences.followed()?.clone();
+        let uri = presence.uri.clone();
+        map_positions(&mut presence, |positions| {
+            Ok(self.positions_to_editor(&uri, positions))
+        })
+        .ok()?;
+        Some(presence)
+    }
+
+    /// Marks the outstanding change on `uri` as acknowledged.
+    /// Returns the message with the next changes to send to the server, if any.
+    fn acknowledge(
+        &mut self,
+        uri: Url,
+        revision: Revision,
+    ) -> anyhow::Result<Option<ClientMessage>> {
+        let opened = self.opening.remove(&uri);
+        let document = self.get_or_create(uri.clone());
+        let SyncDocument::Ot(ot) = &mut document.sync else {
+            if opened {
+                return Ok(None);
+            }
+            bail!("received an acknowledgement for a crdt document");
+        };
+        Ok(ot.acknowledge(revision)?.map(|content_changes| {
+            change_message(
+                VersionedTextDocumentIdentifier::new(uri, document.version),
+                ot.revision(),
+                content_changes,
+            )
+        }))
+    }
+
+    /// Applies a change coming from the server.
+    /// Returns the change to apply in the editor, unless it was already applied.
+    fn remote_change(&mut self, msg: CommonMessage) -> anyhow::Result<Option<Echo>> {
+        let uri = msg.uri().clone();
+        let before = self.get_or_create(uri.clone()).text();
+        let mut echo = self.remote_sync_change(msg)?;
+        if let Some(echo) = &mut echo {
+            self.move_presences(&uri, &before, &echo.change.content_changes);
+            echo.change.content_changes = self.to_editor(&before, &echo.change.content_changes);
+        }
+        Ok(echo)
+    }
+
+    fn remote_sync_change(&mut self, msg: CommonMessage) -> anyhow::Result<Option<Echo>> {
+        match msg {
+            CommonMessage::Change(change) => {
+                let document = self.get_or_create(change.change.text_document.uri.clone());
+                let SyncDocument::Ot(ot) = &mut document.sync else {
+                    bail!("received an ot change for a crdt document");
+                };
+                // sent again by the server because it was not acknowledged in time
+                if change.revision <= ot.revision() {
+                    return Ok(None);
+                }
+                Ok(Some(Echo {
+                    change: DidChangeTextDocumentParams {
+                        content_changes: ot
+                            .remote_change(change.revision, &change.change.content_changes)?,
+                        text_document: change.change.text_document,
+                    },
+                    text: ot.text().to_owned(),
+                    author: change.author,
+                }))
+            }
+            CommonMessage::CrdtChange(change) => {
+                let document = self.get_or_create(change.uri.clone());
+                let SyncDocument::Crdt(crdt) = &mut document.sync else {
+                    bail!("received a crdt change for an ot document");
+                };
+                // merging changes which were already applied gives no content changes
+                let content_changes = crdt.remote_change(&change.changes)?;
+                if content_changes.is_empty() {
+                    return Ok(None);
+                }
+                Ok(Some(Echo {
+                    change: DidChangeTextDocumentParams {
+                        content_changes,
+                        text_document: VersionedTextDocumentIdentifier::new(
+                            change.uri,
+                            document.version,
+                        ),
+                    },
+                    text: crdt.text(),
+                    author: change.author,
+                }))
+            }
+        }
+    }
+}
+
+fn change_message(
+    text_document: VersionedTextDocumentIdentifier,
+    revision: Revision,
+    content_changes: Vec<TextDocumentContentChangeEvent>,
+) -> ClientMessage {
+    ClientMessage::Common(CommonMessage::Change(Change {
+        id: Uuid::new_v4(),
+        author: None,
+        revision,
+        change: DidChangeTextDocumentParams {
+            text_document,
+            content_changes,
+        },
+    }))
+}
+
+/// Delay between two checks of the documents against the server
+const CHECKSUM_INTERVAL: Duration = Duration::from_secs(5);
+/// Delay after which changes waiting for a missing version are applied anyway
+const REORDER_TIMEOUT: Duration = Duration::from_millis(100);
+/// Joins the session given as argument, reconnecting to the server after
+/// [`LEAVE_COMMAND`], in which case the argument is optional
+const JOIN_COMMAND: &str = "codlab.join";
+/// Disconnects from the server, the documents are not shared anymore
+const LEAVE_COMMAND: &str = "codlab.leave";
+/// Shows the peers of the session and the documents they have open
+const LIST_PEERS_COMMAND: &str = "codlab.listPeers";
+/// Restores the document given as argument, or every document, from the server
+const RESYNC_COMMAND: &str = "codlab.resync";
+/// Follows the peer with the name or the id given as argument, or stops following it if it
+/// was followed already. Without argument, stops following anyone.
+const FOLLOW_COMMAND: &str = "codlab.follow";
+/// Commands the editor can run with `workspace/executeCommand`
+const COMMANDS: [&str; 5] = [
+    JOIN_COMMAND,
+    LEAVE_COMMAND,
+    LIST_PEERS_COMMAND,
+    RESYNC_COMMAND,
+    FOLLOW_COMMAND,
+];
+
+/// Applies the changes waiting for `missing` if it did not arrive in time
+struct FlushChanges {
+    uri: Url,
+    missing: i32,
+}
+
 struct ServerState {
-    #[allow(dead_code)]
     client: ClientSocket,
-    codelab_server: Arc<Mutex<CodelabServer>>,
-    ignore_queue_recv: PeekableReceiver<Vec<change::ChangeEvent>>,
-    ignore_queue_send: Sender<Vec<change::ChangeEvent>>,
-    ignore_pool: Vec<change::ChangeEvent>,
+    codelab_server: ServerConnection,
+    documents: SharedDocuments,
+    /// Remote changes applied in the editor, which it will send back
+    echoes: EchoFilter,
+    /// Order of the changes sent by the editor
+    versions: HashMap<Url, VersionTracker>,
+    /// Identifies this client in the presences sent to peers
+    user: Uuid,
+    /// Last cursor and selections sent to peers
+    presence: Option<Presence>,
+    /// Settings given on the command line, which take precedence over the initialization options
+    args: Args,
+    role: Role,
+    /// Set in `initialize`, to reconnect after leaving
+    login: Login,
+}
+
+/// Settings given by the editor in the `initialize` request
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct InitializationOptions {
+    /// Name shown to the peers
+    username: Option<String>,
+    /// Secret required by the server
+    token: Option<String>,
+    role: Option<Role>,
+    /// Save the documents when a peer saves them
+    save_with_peers: bool,
 }
 
 impl LanguageServer for ServerState {
@@ -58,10 +707,67 @@ impl LanguageServer for ServerState {
     ) -> BoxFuture<'static, Result<InitializeResult, Self::Error>> {
         info!("Initialized");
         debug!("Initialize params: {params:?}");
+        let workspace = Workspace::from_params(&params);
+        match workspace.root() {
+            Some(root) => info!("Documents are shared relative to {root}"),
+            None => warn!("No workspace, documents are shared with their absolute URI"),
+        }
+        let options: InitializationOptions = params
+            .initialization_options
+            .and_then(|options| {
+                serde_json::from_value(options)
+                    .inspect_err(|err| warn!("Invalid initialization options: {err:#}"))
+                    .ok()
+            })
+            .unwrap_or_default();
+        self.role = self.args.role.or(options.role).unwrap_or_default();
+        self.login = Login {
+            server_addr: self.args.server_addr.clone(),
+            token: self.args.token.clone().or(options.token),
+            username: self.args.username.clone().or(options.username),
+            role: self.role,
+            workspace,
+        };
+        let encoding = PositionEncoding::negotiate(
+            params
+                .capabilities
+                .general
+                .as_ref()
+                .and_then(|general| general.position_encodings.as_deref()),
+        );
+        info!("Positions are encoded in {encoding:?}");
+        {
+            let mut documents = self.documents.lock().unwrap();
+            documents.encoding = encoding;
+            documents.save_with_peers = self.args.save_with_peers || options.save_with_peers;
+            documents.workspace = self.login.workspace.clone();
+        }
+        let client = self.client.clone();
+        let send = self.codelab_server.clone();
+        let documents = self.documents.clone();
+        let login = self.login.clone();
         Box::pin(async move {
+            log_in(client, &send, documents, &login)
+                .await
+                .map_err(request_failed)?;
             Ok(InitializeResult {
                 capabilities: ServerCapabilities {
-                    text_document_sync: Some(Kind(TextDocumentSyncKind::FULL)),
+                    position_encoding: Some(encoding.kind()),
+                    text_document_sync: Some(TextDocumentSyncCapability::Options(
+                        TextDocumentSyncOptions {
+                            open_close: Some(true),
+                            change: Some(TextDocumentSyncKind::INCREMENTAL),
+                            save: Some(TextDocumentSyncSaveOptions::Supported(true)),
+                            ..TextDocumentSyncOptions::default()
+                        },
+                    )),
+                    // requested when the cursor or the selection moves, see `codlab::presence`
+                    document_highlight_provider: Some(OneOf::Left(true)),
+                    code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
+                    execute_command_provider: Some(ExecuteCommandOptions {
+                        commands: COMMANDS.map(str::to_owned).to_vec(),
+                        ..ExecuteCommandOptions::default()
+                    }),
                     ..ServerCapabilities::default()
                 },
                 server_info: None,
@@ -76,133 +782,935 @@ impl LanguageServer for ServerState {
         ControlFlow::Continue(())
     }
 
+    fn initialized(&mut self, _: InitializedParams) -> Self::NotifyResult {
+        spawn_send(&self.codelab_server, ClientMessage::RequestSnapshot);
+        ControlFlow::Continue(())
+    }
+
     fn did_open(&mut self, params: DidOpenTextDocumentParams) -> Self::NotifyResult {
-        // TODO: open document for peers
-        info!("opened document: {}", params.text_document.uri);
+        let uri = params.text_document.uri;
+        info!("opened document: {uri}");
+        self.versions.insert(
+            uri.clone(),
+            VersionTracker::new(params.text_document.version),
+        );
+        let opened = self.documents.lock().unwrap().open(
+            uri.clone(),
+            params.text_document.text,
+            params.text_document.version,
+            self.role == Role::Editor,
+        );
+        let (msg, echo) = match opened {
+            Ok(opened) => opened,
+            Err(err) => {
+                error!("Failed to share {uri}: {err:#}");
+                return ControlFlow::Continue(());
+            }
+        };
+        spawn_send(&self.codelab_server, msg);
+        if let Some(echo) = echo {
+            info!("{uri} is outdated, updating it");
+            let mut client = self.client.clone();
+            tokio::spawn(async move {
+                if let Err(err) = apply_remote_change(&mut client, echo).await {
+                    error!("Failed to update {uri}: {err:#}");
+                }
+            });
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn did_close(&mut self, params: DidCloseTextDocumentParams) -> Self::NotifyResult {
+        let uri = params.text_document.uri;
+        info!("closed document: {uri}");
+        self.versions.remove(&uri);
+        self.echoes.forget(&uri);
+        self.documents.lock().unwrap().close(&uri);
+        spawn_send(&self.codelab_server, ClientMessage::CloseDocument(uri));
+        ControlFlow::Continue(())
+    }
+
+    fn did_save(&mut self, params: DidSaveTextDocumentParams) -> Self::NotifyResult {
+        let uri = params.text_document.uri;
+        debug!("saved document: {uri}");
+        spawn_send(&self.codelab_server, ClientMessage::SaveDocument(uri));
         ControlFlow::Continue(())
     }
 
     fn did_change(&mut self, params: DidChangeTextDocumentParams) -> Self::NotifyResult {
-        tokio::spawn({
-            let send = self.codelab_server.clone();
-            async move {
-                client_send_msg(
-                    &send,
-                    &ClientMessage::Common(CommonMessage::Change(Change {
-                        change: params,
-                        id: Uuid::new_v4(),
-                    })),
-                )
-                .await;
+        let uri = params.text_document.uri.clone();
+        let version = params.text_document.version;
+        let tracker = self
+            .versions
+            .entry(uri.clone())
+            .or_insert_with(|| VersionTracker::new(version - 1));
+        match tracker.push(params) {
+            Ordered::Ready(ready) => {
+                for params in ready {
+                    self.local_change(params);
+                }
             }
-        });
+            Ordered::Buffered { missing } => {
+                warn!("{uri}: waiting for version {missing} before applying version {version}");
+                let client = self.client.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(REORDER_TIMEOUT).await;
+                    let _ = client.emit(FlushChanges { uri, missing });
+                });
+            }
+            Ordered::Duplicate => warn!("{uri}: ignoring duplicated change at version {version}"),
+            Ordered::Desync => resync(
+                &mut self.client,
+                &self.codelab_server,
+                &self.documents,
+                uri,
+                &format!("the change at version {version} arrived after newer ones"),
+            ),
+        }
         ControlFlow::Continue(())
     }
+
+    fn document_highlight(
+        &mut self,
+        params: DocumentHighlightParams,
+    ) -> BoxFuture<'static, Result<Option<Vec<DocumentHighlight>>, Self::Error>> {
+        let uri = params.text_document_position_params.text_document.uri;
+        let cursor = params.text_document_position_params.position;
+        // the selection ending at the cursor was given by a code action request
+        let selections = match &self.presence {
+            Some(presence) if presence.uri == uri && presence.cursor == cursor => {
+                presence.selections.clone()
+            }
+            _ => vec![],
+        };
+        self.move_presence(uri.clone(), cursor, selections);
+        let highlights = self.documents.lock().unwrap().highlights(&uri);
+        Box::pin(async move { Ok(Some(highlights)) })
+    }
+
+    fn code_action(
+        &mut self,
+        params: CodeActionParams,
+    ) -> BoxFuture<'static, Result<Option<CodeActionResponse>, Self::Error>> {
+        let range = params.range;
+        let selections = if range.start == range.end {
+            vec![]
+        } else {
+            vec![range]
+        };
+        self.move_presence(params.text_document.uri, range.end, selections);
+        Box::pin(async move { Ok(None) })
+    }
+
+    fn execute_command(
+        &mut self,
+        params: ExecuteCommandParams,
+    ) -> BoxFuture<'static, Result<Option<serde_json::Value>, Self::Error>> {
+        let arguments = params.arguments;
+        let result = match params.command.as_str() {
+            JOIN_COMMAND => return self.join(arguments),
+            LEAVE_COMMAND => return self.leave(),
+            LIST_PEERS_COMMAND => self.list_peers(),
+            RESYNC_COMMAND => self.resync_documents(arguments),
+            FOLLOW_COMMAND => self.follow(arguments),
+            command => Err(ResponseError::new(
+                ErrorCode::INVALID_PARAMS,
+                format!("codlab: unknown command {command}"),
+            )),
+        };
+        Box::pin(async move { result })
+    }
 }
 
-async fn client_send_msg(send: &Arc<Mutex<CodelabServer>>, msg: &ClientMessage) {
-    send.lock()
+fn request_failed(err: anyhow::Error) -> ResponseError {
+    error!("{err:#}");
+    ResponseError::new(ErrorCode::REQUEST_FAILED, format!("codlab: {err:#}"))
+}
+
+/// First argument of a command, if given
+fn string_argument(arguments: &[serde_json::Value]) -> Result<Option<String>, ResponseError> {
+    match arguments.first() {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(serde_json::Value::String(argument)) => Ok(Some(argument.clone())),
+        Some(argument) => Err(ResponseError::new(
+            ErrorCode::INVALID_PARAMS,
+            format!("codlab: expected a string argument, got {argument}"),
+        )),
+    }
+}
+
+/// Restores a document from its content on the server, after finding out it is out of sync
+fn resync(
+    client: &mut ClientSocket,
+    send: &ServerConnection,
+    documents: &SharedDocuments,
+    uri: Url,
+    reason: &str,
+) {
+    error!("{uri} is out of sync: {reason}");
+    let _ = client.show_message(ShowMessageParams {
+        typ: MessageType::WARNING,
+        message: format!("codlab: {uri} is out of sync ({reason}), restoring it from the server"),
+    });
+    documents.lock().unwrap().desync(uri);
+    spawn_send(send, ClientMessage::RequestSnapshot);
+}
+
+/// Applies a change coming from the server in the editor
+async fn apply_remote_change(client: &mut ClientSocket, echo: Echo) -> anyhow::Result<()> {
+    let edit = change_event_to_workspace_edit(&echo.change, echo.author.as_deref())?;
+    let label = edit.label.clone().unwrap_or_default();
+    // handled before the editor receives the edit, so before it sends it back
+    client
+        .emit(echo)
+        .context("Failed to notify the language server of a remote change")?;
+    let response = client
+        .apply_edit(edit)
+        .await
+        .context("Failed to send the remote edit to the editor")?;
+    if !response.applied {
+        bail!(
+            "the editor did not apply the remote edit: {}",
+            response.failure_reason.unwrap_or_default()
+        );
+    }
+    debug!("client: applied remote edit ({label})");
+    Ok(())
+}
+
+/// Opens a document shared by a peer in the editor, creating its file in the workspace if needed
+/// so that the edits have somewhere to go
+async fn open_shared_document(
+    client: &mut ClientSocket,
+    workspace: &Workspace,
+    uri: Url,
+) -> anyhow::Result<()> {
+    if workspace.file_path(&uri).is_some_and(|path| !path.exists()) {
+        let create = CreateFile {
+            uri: uri.clone(),
+            options: Some(CreateFileOptions {
+                overwrite: Some(false),
+                ignore_if_exists: Some(true),
+            }),
+            annotation_id: None,
+        };
+        let response = client
+            .apply_edit(ApplyWorkspaceEditParams {
+                label: Some("codlab: shared document".to_owned()),
+                edit: WorkspaceEdit {
+                    document_changes: Some(DocumentChanges::Operations(vec![
+                        DocumentChangeOperation::Op(ResourceOp::Create(create)),
+                    ])),
+                    ..WorkspaceEdit::default()
+                },
+            })
+            .await
+            .context("Failed to ask the editor to create the file")?;
+        if !response.applied {
+            bail!(
+                "the editor did not create the file: {}",
+                response.failure_reason.unwrap_or_default()
+            );
+        }
+    }
+    client
+        .show_document(ShowDocumentParams {
+            uri,
+            external: Some(false),
+            // don't interrupt the user
+            take_focus: Some(false),
+            selection: None,
+        })
         .await
-        .send(tokio_tungstenite::tungstenite::Message::Text(
-            serde_json::to_string(msg)
+        .context("Failed to ask the editor to show the document")?;
+    Ok(())
+}
+
+/// Shows the document and the selection, or the cursor, of a followed peer in the editor
+async fn reveal_presence(mut client: ClientSocket, presence: Presence) {
+    let selection = presence
+        .selections
+        .first()
+        .copied()
+        .unwrap_or(Range::new(presence.cursor, presence.cursor));
+    let shown = client
+        .show_document(ShowDocumentParams {
+            uri: presence.uri.clone(),
+            external: Some(false),
+            take_focus: Some(true),
+            selection: Some(selection),
+        })
+        .await;
+    match shown {
+        Ok(result) if result.success => {}
+        Ok(_) => warn!("The editor could not show {}", presence.uri),
+        Err(err) => warn!("Failed to follow the peer to {}: {err:#}", presence.uri),
+    }
+}
+
+/// Replaces a document in the editor with its content on the server
+async fn restore_snapshot(
+    client: &mut ClientSocket,
+    documents: &SharedDocuments,
+    snapshot: Snapshot,
+) {
+    info!("client: restoring {} from snapshot", snapshot.uri);
+    let change = documents.lock().unwrap().restore(snapshot);
+    let restored = match change {
+        Ok(Some(change)) => apply_remote_change(client, change).await,
+        Ok(None) => {
+            debug!("client: document is already up to date");
+            Ok(())
+        }
+        Err(err) => Err(err),
+    };
+    if let Err(err) = restored {
+        error!("Failed to restore snapshot: {err:#}");
+    }
+}
+
+async fn client_send_msg(send: &ServerConnection, mut msg: ClientMessage) -> anyhow::Result<()> {
+    let mut send = send.lock().await;
+    let Some(connection) = send.as_mut() else {
+        bail!("Not connected to the server, dropping message {msg:?}");
+    };
+    for uri in msg.uris_mut() {
+        *uri = connection.workspace.to_wire(uri);
+    }
+    let sent = connection
+        .sink
+        .send(Message::Text(
+            serde_json::to_string(&msg)
                 .expect("To be able to construct a json")
                 .into(),
         ))
-        .await
-        .expect("Failed to send message to server");
+        .await;
+    if sent.is_err() {
+        // the connection is broken, `codlab.join` connects again
+        *send = None;
+    }
+    sent.context("Failed to send message to server")
 }
 
-fn content_changes_eq(
-    a: &TextDocumentContentChangeEvent,
-    b: &TextDocumentContentChangeEvent,
-) -> bool {
-    a.range == b.range && a.text == b.text
+/// Sends `msg` to the server in the background, logging the errors
+fn spawn_send(send: &ServerConnection, msg: ClientMessage) {
+    let send = send.clone();
+    tokio::spawn(async move {
+        if let Err(err) = client_send_msg(&send, msg).await {
+            error!("{err:#}");
+        }
+    });
 }
 
-fn changes_eq(a: &DidChangeTextDocumentParams, b: &DidChangeTextDocumentParams) -> bool {
-    let eq = a.text_document.uri == b.text_document.uri
-        && a.content_changes
-            .iter()
-            .zip(b.content_changes.iter())
-            .all(|(a, b)| content_changes_eq(a, b));
-    // if !eq {
-    //     info!("{:#?} == {:#?}", &a.content_changes, &b.content_changes);
-    // }
-    eq
+fn close_reason(frame: Option<&CloseFrame>) -> String {
+    match frame {
+        Some(frame) if !frame.reason.is_empty() => frame.reason.to_string(),
+        Some(frame) => frame.code.to_string(),
+        None => "no reason given".to_owned(),
+    }
+}
+
+/// Connects to the server and introduces the user
+async fn log_in(
+    client: ClientSocket,
+    send: &ServerConnection,
+    documents: SharedDocuments,
+    login: &Login,
+) -> anyhow::Result<()> {
+    connect(
+        client,
+        send.clone(),
+        documents,
+        &login.server_addr,
+        login.token.as_deref(),
+        login.workspace.clone(),
+    )
+    .await?;
+    let hello = ClientMessage::Hello {
+        username: login.username.clone(),
+        role: login.role,
+    };
+    client_send_msg(send, hello).await
+}
+
+/// Connects to the server, giving it `token` in the handshake, and starts handling its messages
+async fn connect(
+    mut client: ClientSocket,
+    send: ServerConnection,
+    documents: SharedDocuments,
+    server_addr: &str,
+    token: Option<&str>,
+    workspace: Workspace,
+) -> anyhow::Result<()> {
+    let mut request = server_addr
+        .into_client_request()
+        .context("Invalid server address")?;
+    if let Some(token) = token {
+        request.headers_mut().insert(
+            AUTHORIZATION,
+            format!("Bearer {token}").parse().context("Invalid token")?,
+        );
+    }
+    // changes are small messages which should be sent right away
+    let (ws, _) = connect_async_with_config(request, None, true)
+        .await
+        .context("Could not connect to server")?;
+    let (sink, mut recv) = ws.split();
+    // the server greets the clients it accepts with the sync engine, and closes the connection
+    // of the others
+    let greeting = recv
+        .try_next()
+        .await
+        .context("Failed to recv the greeting of the server")?;
+    match greeting {
+        Some(Message::Close(frame)) => bail!(
+            "the server refused the connection: {}",
+            close_reason(frame.as_ref())
+        ),
+        Some(msg) => {
+            match serde_json::from_str(msg.to_text()?).context("Server sent an invalid message")? {
+                ServerMessage::SyncEngine(engine) => {
+                    info!("client: server uses {engine:?} to sync documents");
+                    documents.lock().unwrap().set_engine(engine);
+                }
+                msg => bail!("expected the server to greet with its sync engine, got {msg:?}"),
+            }
+        }
+        None => bail!("the server closed the connection"),
+    }
+    *send.lock().await = Some(Connection {
+        sink,
+        workspace: workspace.clone(),
+    });
+    tokio::spawn(async move {
+        let received =
+            receive_server_messages(client.clone(), &send, &documents, &workspace, &mut recv).await;
+        let mut send = send.lock().await;
+        // unless the user left, however the connection ended, `codlab.join` connects again
+        let connected = send
+            .as_ref()
+            .is_some_and(|connection| connection.sink.is_pair_of(&recv));
+        if connected {
+            *send = None;
+        }
+        if let Err(err) = received {
+            error!("{err:#}");
+            if connected {
+                let _ = client.show_message(ShowMessageParams {
+                    typ: MessageType::ERROR,
+                    message: format!("codlab: disconnected from the server ({err:#})"),
+                });
+            }
+        }
+    });
+    Ok(())
 }
 
 impl ServerState {
     fn new_router(
         editor_client: ClientSocket,
-        codelab_server: Arc<Mutex<CodelabServer>>,
+        codelab_server: ServerConnection,
+        documents: SharedDocuments,
+        args: Args,
     ) -> Router<Self> {
-        let (ignore_queue_send, ignore_queue_recv) = mpsc::channel();
-        let ignore_queue_recv = PeekableReceiver::from(ignore_queue_recv);
         let mut router = Router::from_language_server(Self {
             client: editor_client,
             codelab_server,
-            ignore_queue_recv,
-            ignore_queue_send,
-            ignore_pool: Vec::new(),
+            documents,
+            echoes: EchoFilter::default(),
+            versions: HashMap::new(),
+            user: Uuid::new_v4(),
+            presence: None,
+            args,
+            role: Role::default(),
+            login: Login::default(),
         });
         router.event(Self::on_change);
+        router.event(Self::on_flush_changes);
         router
     }
 
-    fn on_change(&mut self, event: change::ChangeEvent) -> ControlFlow<async_lsp::Result<()>> {
-        // we don't want to send what we just received otherwise we create an infinite loop between clients
-        // self.ignore_queue_send.send(()).unwrap();
+    /// Sends a change made in the editor to the server
+    fn local_change(&mut self, mut params: DidChangeTextDocumentParams) {
+        let uri = params.text_document.uri.clone();
+        let (mut changes, shared) = {
+            let mut documents = self.documents.lock().unwrap();
+            let changes = documents.editor_change(&uri, &params.content_changes);
+            (changes, documents.by_uri.contains_key(&uri))
+        };
+        // we don't want to send what we just received otherwise we create an infinite loop between clients.
+        // Echoes are in the encoding of the editor, and only the last changes can be made in it.
+        let made = self.echoes.filter(&uri, params.content_changes).len();
+        params.content_changes = changes.split_off(changes.len() - made);
+        if self.role == Role::Observer {
+            if !shared {
+                // the documents the session does not share are the observer's own
+                return;
+            }
+            if !params.content_changes.is_empty() {
+                self.reject_local_change(params);
+                return;
+            }
+        }
+        match self.documents.lock().unwrap().local_change(params) {
+            Ok(Some(msg)) => {
+                spawn_send(&self.codelab_server, msg);
+            }
+            // will be sent once the outstanding change is acknowledged
+            Ok(None) => {}
+            Err(err) => error!("Failed to record local change: {err:#}"),
+        }
+    }
+
+    /// Reverts a change made by an observer to the content of the document on the server
+    fn reject_local_change(&mut self, mut params: DidChangeTextDocumentParams) {
+        let uri = params.text_document.uri.clone();
+        warn!("{uri}: rejecting local change, observers can't edit");
+        let _ = self.client.show_message(ShowMessageParams {
+            typ: MessageType::WARNING,
+            message: format!(
+                "codlab: you joined as an observer, your changes to {uri} are reverted"
+            ),
+        });
+        params.content_changes.clear();
+        let mut documents = self.documents.lock().unwrap();
+        // only keeps track of the version
+        if let Err(err) = documents.local_change(params) {
+            error!("Failed to record local change: {err:#}");
+        }
+        documents.desync(uri);
+        spawn_send(&self.codelab_server, ClientMessage::RequestSnapshot);
+    }
+
+    /// See [`JOIN_COMMAND`]
+    fn join(
+        &mut self,
+        arguments: Vec<serde_json::Value>,
+    ) -> BoxFuture<'static, Result<Option<serde_json::Value>, ResponseError>> {
+        let session = string_argument(&arguments);
+        let client = self.client.clone();
+        let send = self.codelab_server.clone();
+        let documents = self.documents.clone();
+        let login = self.login.clone();
+        Box::pin(async move {
+            let session = session?;
+            let connected = send.lock().await.is_some();
+            let msg = match session {
+                Some(session) => ClientMessage::JoinSession(session),
+                // back to the session of the server address
+                None if !connected => ClientMessage::RequestSnapshot,
+                None => {
+                    return Err(ResponseError::new(
+                        ErrorCode::INVALID_PARAMS,
+                        "codlab: expected the name of the session to join",
+                    ));
+                }
+            };
+            if !connected {
+                info!("Reconnecting to {}", login.server_addr);
+                // otherwise cleared once joined
+                if matches!(msg, ClientMessage::RequestSnapshot) {
+                    documents.lock().unwrap().clear();
+                }
+                log_in(client, &send, documents, &login)
+                    .await
+                    .map_err(request_failed)?;
+            }
+            client_send_msg(&send, msg).await.map_err(request_failed)?;
+            Ok(None)
+        })
+    }
+
+    /// See [`LEAVE_COMMAND`]
+    fn leave(&mut self) -> BoxFuture<'static, Result<Option<serde_json::Value>, ResponseError>> {
+        let mut client = self.client.clone();
+        let send = self.codelab_server.clone();
+        let documents = self.documents.clone();
+        Box::pin(async move {
+            let Some(mut connection) = send.lock().await.take() else {
+                return Err(ResponseError::new(
+                    ErrorCode::REQUEST_FAILED,
+                    "codlab: not connected to the server",
+                ));
+            };
+            if let Err(err) = connection.sink.close().await {
+                warn!("Failed to close the connection: {err:#}");
+            }
+            // the documents are kept as they are in the editor, until joining again
+            documents.lock().unwrap().presences.clear();
+            info!("Left the session");
+            let _ = client.show_message(ShowMessageParams {
+                typ: MessageType::INFO,
+                message: "codlab: left the session, the documents are not shared anymore"
+                    .to_owned(),
+            });
+            Ok(None)
+        })
+    }
+
+    /// See [`LIST_PEERS_COMMAND`], the peers are shown once the server sends them
+    fn list_peers(&mut self) -> Result<Option<serde_json::Value>, ResponseError> {
+        spawn_send(&self.codelab_server, ClientMessage::ListPeers);
+        Ok(None)
+    }
+
+    /// See [`RESYNC_COMMAND`]
+    fn resync_documents(
+        &mut self,
+        arguments: Vec<serde_json::Value>,
+    ) -> Result<Option<serde_json::Value>, ResponseError> {
+        let uri = string_argument(&arguments)?
+            .map(|uri| Url::parse(&uri))
+            .transpose()
+            .map_err(|err| {
+                ResponseError::new(ErrorCode::INVALID_PARAMS, format!("codlab: {err}"))
+            })?;
+        let mut documents = self.documents.lock().unwrap();
+        let uris: Vec<_> = match uri {
+            Some(uri) => vec![uri],
+            None => documents.by_uri.keys().cloned().collect(),
+        };
+        for uri in uris {
+            info!("{uri} is restored from the server on request");
+            documents.desync(uri);
+        }
+        spawn_send(&self.codelab_server, ClientMessage::RequestSnapshot);
+        Ok(None)
+    }
+
+    /// Toggles following a peer, see [`FOLLOW_COMMAND`].
+    /// Returns the name or the id of the followed peer.
+    fn follow(
+        &mut self,
+        arguments: Vec<serde_json::Value>,
+    ) -> Result<Option<serde_json::Value>, ResponseError> {
+        let user = string_argument(&arguments)?;
+        let mut documents = self.documents.lock().unwrap();
+        let user = user.filter(|user| documents.presences.following() != Some(user));
+        let message = match &user {
+            Some(user) => format!("codlab: following {user}"),
+            None => "codlab: not following anyone".to_owned(),
+        };
+        info!("{message}");
+        let _ = self.client.show_message(ShowMessageParams {
+            typ: MessageType::INFO,
+            message,
+        });
+        documents.presences.follow(user.clone());
+        // jumps to the peer right away if it is known
+        if let Some(presence) = documents.followed() {
+            tokio::spawn(reveal_presence(self.client.clone(), presence));
+        }
+        Ok(user.map(serde_json::Value::String))
+    }
+
+    /// Sends the cursor and the selections to the peers, if they moved
+    fn move_presence(&mut self, uri: Url, cursor: Position, selections: Vec<Range>) {
+        let presence = Presence {
+            user: self.user,
+            username: None,
+            uri,
+            revision: 0,
+            cursor,
+            selections,
+        };
+        if self.presence.as_ref() == Some(&presence) {
+            return;
+        }
+        self.presence = Some(presence.clone());
+        match self.documents.lock().unwrap().local_presence(presence) {
+            Ok(msg) => {
+                spawn_send(&self.codelab_server, msg);
+            }
+            Err(err) => error!("Failed to send the cursor position: {err:#}"),
+        }
+    }
+
+    fn on_flush_changes(&mut self, flush: FlushChanges) -> ControlFlow<async_lsp::Result<()>> {
+        let Some(tracker) = self.versions.get_mut(&flush.uri) else {
+            return ControlFlow::Continue(());
+        };
+        if tracker.version() >= flush.missing {
+            // arrived in time
+            return ControlFlow::Continue(());
+        }
+        warn!(
+            "{}: version {} never arrived, assuming the editor skipped it",
+            flush.uri, flush.missing
+        );
+        for params in tracker.flush() {
+            self.local_change(params);
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn on_change(&mut self, echo: Echo) -> ControlFlow<async_lsp::Result<()>> {
+        self.echoes.expect(echo);
         ControlFlow::Continue(())
     }
 }
 
-#[derive(Parser)]
+/// Handles the messages of the server until it closes the connection
+async fn receive_server_messages(
+    mut client: ClientSocket,
+    send: &ServerConnection,
+    documents: &SharedDocuments,
+    workspace: &Workspace,
+    recv: &mut SplitStream<WebSocket>,
+) -> anyhow::Result<()> {
+    while let Some(msg) = recv
+        .try_next()
+        .await
+        .context("Failed to recv updates from server")?
+    {
+        if let Message::Close(frame) = &msg {
+            // unless the user left, in which case it may have connected again since
+            if !send
+                .lock()
+                .await
+                .as_ref()
+                .is_some_and(|connection| connection.sink.is_pair_of(recv))
+            {
+                info!("client: disconnected from the server");
+                break;
+            }
+            let reason = close_reason(frame.as_ref());
+            error!("client: the server closed the connection: {reason}");
+            let _ = client.show_message(ShowMessageParams {
+                typ: MessageType::ERROR,
+                message: format!("codlab: disconnected from the server ({reason})"),
+            });
+            break;
+        }
+        let mut msg: ServerMessage =
+            serde_json::from_str(msg.to_text().context("Server sent a non text message")?)
+                .context("Server sent an invalid message")?;
+        // before anything refers to the documents, in the editor as well
+        match &mut msg {
+            ServerMessage::Snapshot(snapshots) => snapshots.retain(|snapshot| {
+                let local = workspace.to_local(&snapshot.uri);
+                if local.is_none() {
+                    warn!(
+                        "client: ignoring {}, outside of the workspace",
+                        snapshot.uri
+                    );
+                }
+                local.is_some()
+            }),
+            ServerMessage::Peers(peers) => {
+                for peer in peers {
+                    peer.open.retain(|uri| workspace.to_local(uri).is_some());
+                }
+            }
+            _ => {}
+        }
+        let mut outside = None;
+        for uri in msg.uris_mut() {
+            match workspace.to_local(uri) {
+                Some(local) => *uri = local,
+                None => outside = Some(uri.clone()),
+            }
+        }
+        if let Some(uri) = outside {
+            warn!("client: ignoring a message about {uri}, outside of the workspace");
+            continue;
+        }
+        match msg {
+            ServerMessage::SyncEngine(engine) => {
+                info!("client: server uses {engine:?} to sync documents");
+                documents.lock().unwrap().set_engine(engine);
+            }
+            ServerMessage::Joined(session) => {
+                info!("client: joined session {session}");
+                documents.lock().unwrap().clear();
+            }
+            ServerMessage::AcknowledgeChange { uri, id, revision } => {
+                debug!("client: change {id} acknowledged at revision {revision}");
+                let next = documents.lock().unwrap().acknowledge(uri, revision);
+                match next {
+                    Ok(Some(msg)) => {
+                        if let Err(err) = client_send_msg(send, msg).await {
+                            error!("{err:#}");
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(err) => error!("Failed to apply acknowledgement: {err:#}"),
+                }
+            }
+            ServerMessage::Snapshot(snapshots) => {
+                for snapshot in snapshots {
+                    restore_snapshot(&mut client, documents, snapshot).await;
+                }
+            }
+            ServerMessage::Correction(snapshot) => {
+                warn!("client: {} is out of sync with the server", snapshot.uri);
+                let _ = client.show_message(ShowMessageParams {
+                    typ: MessageType::WARNING,
+                    message: format!(
+                        "codlab: {} is out of sync, restoring it from the server",
+                        snapshot.uri
+                    ),
+                });
+                documents.lock().unwrap().desync(snapshot.uri.clone());
+                restore_snapshot(&mut client, documents, snapshot).await;
+            }
+            ServerMessage::Presence(presence) => {
+                let presence = documents.lock().unwrap().remote_presence(presence);
+                match presence {
+                    Ok(presence) => {
+                        if documents.lock().unwrap().presences.is_followed(&presence) {
+                            tokio::spawn(reveal_presence(client.clone(), presence.clone()));
+                        }
+                        let _ = client.notify::<PresenceNotification>(presence);
+                    }
+                    Err(err) => debug!("client: dropping presence: {err:#}"),
+                }
+            }
+            ServerMessage::UserLeft(user) => {
+                if documents.lock().unwrap().presences.remove(&user).is_some() {
+                    let _ = client.notify::<UserLeftNotification>(UserLeftParams { user });
+                }
+            }
+            ServerMessage::History(history) => {
+                let _ = client.notify::<HistoryNotification>(history);
+            }
+            ServerMessage::Peers(peers) => {
+                let _ = client.show_message(ShowMessageParams {
+                    typ: MessageType::INFO,
+                    message: describe_peers(&peers, workspace),
+                });
+                let _ = client.notify::<PeersNotification>(PeersParams { peers });
+            }
+            ServerMessage::DocumentSaved(saved) => {
+                info!(
+                    "client: {} saved {}",
+                    saved.author.as_deref().unwrap_or("a peer"),
+                    saved.uri
+                );
+                if let Err(err) = documents.lock().unwrap().save(&saved.uri) {
+                    warn!("Failed to save {}: {err:#}", saved.uri);
+                }
+                let _ = client.notify::<DocumentSavedNotification>(saved);
+            }
+            ServerMessage::DocumentOpened { author, snapshot } => {
+                info!(
+                    "client: {} shared {}",
+                    author.as_deref().unwrap_or("a peer"),
+                    snapshot.uri
+                );
+                if let Err(err) =
+                    open_shared_document(&mut client, workspace, snapshot.uri.clone()).await
+                {
+                    warn!("Failed to open {}: {err:#}", snapshot.uri);
+                }
+                restore_snapshot(&mut client, documents, snapshot).await;
+            }
+            ServerMessage::Common(common_message) => {
+                let id = common_message.id();
+                let uri = common_message.uri().clone();
+                let change = documents.lock().unwrap().remote_change(common_message);
+                let applied = match change {
+                    Ok(Some(change)) => apply_remote_change(&mut client, change).await,
+                    Ok(None) => {
+                        debug!("client: change {id} was already applied");
+                        Ok(())
+                    }
+                    Err(err) => Err(err),
+                };
+                match applied {
+                    // the server only considers the change delivered once acknowledged
+                    Ok(()) => {
+                        let ack = ClientMessage::AcknowledgeChange(id);
+                        if let Err(err) = client_send_msg(send, ack).await {
+                            error!("{err:#}");
+                        }
+                    }
+                    Err(err) => resync(
+                        &mut client,
+                        send,
+                        documents,
+                        uri,
+                        &format!("failed to apply remote change {id}: {err:#}"),
+                    ),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Lists the peers of the session for the user
+fn describe_peers(peers: &[Peer], workspace: &Workspace) -> String {
+    if peers.is_empty() {
+        return "codlab: you are alone in the session".to_owned();
+    }
+    let peers: Vec<_> = peers
+        .iter()
+        .map(|peer| {
+            let mut description = match &peer.name {
+                Some(name) => name.clone(),
+                None => format!("#{}", peer.id),
+            };
+            if peer.role == Role::Observer {
+                description.push_str(" (observer)");
+            }
+            if !peer.open.is_empty() {
+                let open: Vec<_> = peer
+                    .open
+                    .iter()
+                    .map(|uri| workspace.relative_path(uri).unwrap_or(uri.as_str()))
+                    .collect();
+                description.push_str(&format!(" in {}", open.join(", ")));
+            }
+            description
+        })
+        .collect();
+    format!("codlab: peers of the session: {}", peers.join("; "))
+}
+
+#[derive(Parser, Clone)]
 struct Args {
+    /// Address of the server, the path selects the session to join (ws://localhost:7575/my-team,
+    /// wss:// if the server uses TLS)
     server_addr: String,
+    /// Name shown to the peers, can also be given in the `username` initialization option
+    #[arg(long)]
+    username: Option<String>,
+    /// Secret required by the server, can also be given in the `token` initialization option
+    #[arg(long, env = "CODLAB_TOKEN")]
+    token: Option<String>,
+    /// Join as an observer to watch without editing, can also be given in the `role`
+    /// initialization option [default: editor]
+    #[arg(long, value_enum)]
+    role: Option<Role>,
+    /// Save the documents of the workspace when a peer saves them, writing their shared content
+    /// to their file, can also be given in the `saveWithPeers` initialization option
+    #[arg(long)]
+    save_with_peers: bool,
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
-    let (ws, _) = connect_async(args.server_addr)
-        .await
-        .context("Could not connect to server")?;
-    let (send, mut recv) = ws.split();
-    let send = Arc::new(Mutex::new(send));
+    let send = ServerConnection::default();
+    let documents = SharedDocuments::default();
 
     let (server, _) = async_lsp::MainLoop::new_server(|client| {
         tokio::spawn({
-            let mut client = client.clone();
-            let _send = send.clone();
+            let send = send.clone();
+            let documents = documents.clone();
             async move {
-                while let Some(msg) = recv
-                    .try_next()
-                    .await
-                    .context("Failed to recv updates from server")?
-                {
-                    let msg: ServerMessage = serde_json::from_str(
-                        msg.to_text().context("Server sent a non text message")?,
-                    )
-                    .context("Server sent an invalid message")?;
-                    match msg {
-                        ServerMessage::Common(common_message) => match common_message {
-                            CommonMessage::Change(change) => {
-                                if client
-                                    .emit(change::ChangeEvent::new(change.change.clone()))
-                                    .is_err()
-                                {
-                                    break;
-                                }
-                                client
-                                    .apply_edit(change_event_to_workspace_edit(&change.change))
-                                    .await
-                                    .unwrap();
-                                debug!("client: applied remote edit successfully!");
-                            }
-                        },
+                let mut interval = tokio::time::interval(CHECKSUM_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    let checksums = documents.lock().unwrap().checksums();
+                    if !checksums.is_empty() {
+                        let msg = ClientMessage::Checksums(checksums);
+                        if let Err(err) = client_send_msg(&send, msg).await {
+                            error!("{err:#}");
+                        }
                     }
                 }
-                Ok::<(), anyhow::Error>(())
             }
         });
 
@@ -212,7 +1720,12 @@ async fn main() -> anyhow::Result<()> {
             .layer(CatchUnwindLayer::default())
             .layer(ConcurrencyLayer::default())
             .layer(ClientProcessMonitorLayer::new(client.clone()))
-            .service(ServerState::new_router(client, send))
+            .service(ServerState::new_router(
+                client,
+                send.clone(),
+                documents.clone(),
+                args.clone(),
+            ))
     });
 
     init_logger();
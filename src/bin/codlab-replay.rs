@@ -0,0 +1,230 @@
+use std::{
+    collections::BTreeMap,
+    io::Write as _,
+    path::{Component, Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{Context, bail};
+use async_lsp::lsp_types::Url;
+use clap::{Parser, Subcommand};
+use codlab::{
+    buffer::Buffer,
+    messages::{Edit, Revision},
+    recording,
+};
+use similar::TextDiff;
+
+/// Rebuilds the documents of a session from the changes recorded by the server with --record
+#[derive(Parser)]
+struct Args {
+    /// File written by the server
+    recording: PathBuf,
+    /// Session whose documents are rebuilt
+    #[arg(long, default_value = "default")]
+    session: String,
+    #[command(subcommand)]
+    command: Action,
+}
+
+#[derive(Subcommand)]
+enum Action {
+    /// Lists the documents of the session with their last revision
+    List,
+    /// Prints a document at a revision
+    Show {
+        uri: Url,
+        /// Revision to rebuild [default: the last one]
+        #[arg(long)]
+        revision: Option<Revision>,
+    },
+    /// Prints every edit as a unified diff
+    Diff {
+        /// Only the edits of this document
+        #[arg(long)]
+        uri: Option<Url>,
+    },
+    /// Commits every edit to a git repository, created if needed. Only its history is written
+    /// to, not its worktree.
+    Git {
+        repository: PathBuf,
+        /// Only the edits of this document
+        #[arg(long)]
+        uri: Option<Url>,
+    },
+}
+
+/// Path of a document in the diffs and in the git repository, which it must not escape nor
+/// reach the internals of
+fn path(uri: &Url) -> anyhow::Result<String> {
+    let path = uri.path().trim_start_matches('/');
+    let mut components = Path::new(path).components().peekable();
+    if components.peek().is_none()
+        || !components.all(|component| match component {
+            Component::Normal(name) => !name.eq_ignore_ascii_case(".git"),
+            _ => false,
+        })
+    {
+        bail!("{uri} does not name a file inside the repository");
+    }
+    Ok(path.to_owned())
+}
+
+fn author(edit: &Edit) -> &str {
+    edit.author.as_deref().unwrap_or("anonymous")
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let edits = recording::edits(recording::read(&args.recording)?, &args.session)?;
+    if edits.is_empty() {
+        bail!("no change was recorded in session {}", args.session);
+    }
+    match args.command {
+        Action::List => {
+            let mut revisions = BTreeMap::new();
+            for (uri, edit) in &edits {
+                revisions.insert(uri, edit.revision);
+            }
+            for (uri, revision) in revisions {
+                println!("{uri} {revision}");
+            }
+        }
+        Action::Show { uri, revision } => {
+            let edits: Vec<_> = edits
+                .iter()
+                .filter(|(edit_uri, _)| *edit_uri == uri)
+                .map(|(_, edit)| edit)
+                .collect();
+            if edits.is_empty() {
+                bail!("{uri} was not edited in session {}", args.session);
+            }
+            print!(
+                "{}",
+                recording::text_at(edits, revision.unwrap_or(Revision::MAX))
+            );
+        }
+        Action::Diff { uri } => {
+            for_each_edit(&edits, uri.as_ref(), |uri, edit, before, after| {
+                let path = path(uri)?;
+                println!(
+                    "# {path} revision {} by {} at {}",
+                    edit.revision,
+                    author(edit),
+                    edit.timestamp
+                );
+                print!(
+                    "{}",
+                    TextDiff::from_lines(before, after)
+                        .unified_diff()
+                        .header(&format!("a/{path}"), &format!("b/{path}"))
+                );
+                Ok(())
+            })?;
+        }
+        Action::Git { repository, uri } => {
+            if !repository.join(".git").exists() {
+                std::fs::create_dir_all(&repository)?;
+                git(&repository, &["init", "--quiet"], None, None)?;
+            }
+            for_each_edit(&edits, uri.as_ref(), |uri, edit, _, after| {
+                let path = path(uri)?;
+                // staged without going through the worktree, which the recording must not
+                // write to
+                let blob = git(
+                    &repository,
+                    &["hash-object", "-w", "--stdin"],
+                    None,
+                    Some(after),
+                )?;
+                let entry = format!("100644,{blob},{path}");
+                git(
+                    &repository,
+                    &["update-index", "--add", "--cacheinfo", &entry],
+                    None,
+                    None,
+                )?;
+                let message = format!("{path}: revision {}", edit.revision);
+                let author = format!("{0} <{0}@codlab>", author(edit));
+                git(
+                    &repository,
+                    &[
+                        "commit",
+                        "--quiet",
+                        "--allow-empty",
+                        "--message",
+                        &message,
+                        "--author",
+                        &author,
+                    ],
+                    Some(edit.timestamp),
+                    None,
+                )
+                .map(|_| ())
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Calls `f` with the content of the document before and after each edit
+fn for_each_edit(
+    edits: &[(Url, Edit)],
+    only: Option<&Url>,
+    mut f: impl FnMut(&Url, &Edit, &str, &str) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut buffers = BTreeMap::<&Url, Buffer>::new();
+    for (uri, edit) in edits {
+        if only.is_some_and(|only| only != uri) {
+            continue;
+        }
+        let buffer = buffers.entry(uri).or_default();
+        let before = buffer.to_string();
+        buffer.apply_changes(&edit.changes);
+        f(uri, edit, &before, &buffer.to_string())?;
+    }
+    Ok(())
+}
+
+/// Runs git in `repository`, dating the commits at `timestamp` (milliseconds since the Unix epoch)
+/// and giving it `input`. Returns what it printed, trimmed.
+fn git(
+    repository: &Path,
+    args: &[&str],
+    timestamp: Option<u64>,
+    input: Option<&str>,
+) -> anyhow::Result<String> {
+    let mut command = Command::new("git");
+    command
+        .arg("-C")
+        .arg(repository)
+        .args(args)
+        .env("GIT_COMMITTER_NAME", "codlab")
+        .env("GIT_COMMITTER_EMAIL", "codlab@localhost");
+    if let Some(timestamp) = timestamp {
+        let date = format!("@{} +0000", timestamp / 1000);
+        command
+            .env("GIT_AUTHOR_DATE", &date)
+            .env("GIT_COMMITTER_DATE", &date);
+    }
+    command
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped());
+    let mut child = command.spawn().context("Failed to run git")?;
+    if let Some(input) = input {
+        // closed once written, so that git stops reading
+        let mut stdin = child.stdin.take().expect("stdin to be piped");
+        stdin
+            .write_all(input.as_bytes())
+            .context("Failed to write to git")?;
+    }
+    let output = child.wait_with_output().context("Failed to run git")?;
+    if !output.status.success() {
+        bail!("git {} failed with {}", args.join(" "), output.status);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
@@ -1,37 +1,735 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
-use anyhow::Context;
+use anyhow::{Context, bail};
+use async_lsp::lsp_types::{DidChangeTextDocumentParams, TextDocumentContentChangeEvent, Url};
+use clap::Parser;
 use codlab::{
-    common::init_logger,
-    messages::{ClientMessage, ServerMessage},
+    buffer::Buffer,
+    common::init_logger_with_level,
+    config::{ServerConfig, Tls},
+    crdt,
+    history::History,
+    messages::{
+        Change, Checksum, ClientMessage, CommonMessage, EditHistory, Peer, Presence, Revision,
+        Role, SavedDocument, ServerMessage, Snapshot, SyncEngine,
+    },
+    ot,
+    presence::map_positions,
+    recording::{self, Recorder},
+    storage::{
+        LogEntry, SNAPSHOT_INTERVAL, SessionSnapshot, SessionStorage, Storage, StoredDocument,
+    },
 };
-use futures::{SinkExt, StreamExt, TryStreamExt as _, future::join_all, stream::SplitSink};
+use futures::{SinkExt, StreamExt, TryStreamExt as _, stream::SplitSink};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject as _};
 use tokio::{
+    io::{AsyncRead, AsyncWrite},
     net::{TcpListener, TcpStream},
-    sync::Mutex,
+    sync::{Mutex, mpsc, watch},
+    time::Instant,
+};
+use tokio_rustls::{TlsAcceptor, rustls};
+use tokio_tungstenite::{
+    WebSocketStream,
+    tungstenite::{
+        self,
+        handshake::server::Request,
+        http::header::AUTHORIZATION,
+        protocol::{CloseFrame, frame::coding::CloseCode},
+    },
 };
-use tokio_tungstenite::{WebSocketStream, tungstenite};
-use tracing::{debug, error, info};
-// TODO: config
-const LISTEN_ADDR: &str = "0.0.0.0:7575";
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+/// Session of the clients connecting without a path in the URL
+const DEFAULT_SESSION: &str = "default";
+/// Delay after which a change which was not acknowledged is sent again
+const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Number of times a change is sent again before giving up on the client
+const MAX_RESENDS: u32 = 3;
+/// Changes a client may leave unacknowledged before it is disconnected
+const MAX_UNACKNOWLEDGED: usize = 1000;
+/// Messages waiting to be written to the socket of a client before it is disconnected
+const OUTGOING_CAPACITY: usize = 1000;
+/// Time given to a client to complete the TLS and websocket handshakes
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Delay after which a client which does not read its socket is disconnected
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A change sent to a client which did not acknowledge it yet
+struct PendingChange {
+    id: Uuid,
+    msg: tungstenite::Message,
+    sent_at: Instant,
+    /// Number of times the change was sent again
+    resent: u32,
+}
+
+/// A TCP stream, wrapped in TLS when serving `wss://`
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+type WebSocketSink = SplitSink<WebSocketStream<Box<dyn Stream>>, tungstenite::Message>;
 
 struct Client {
-    send: SplitSink<WebSocketStream<TcpStream>, tokio_tungstenite::tungstenite::Message>,
-    #[allow(dead_code)]
+    /// Messages written to the socket by [`write_messages`], so that a slow client does not
+    /// hold up its peers
+    outgoing: mpsc::Sender<tungstenite::Message>,
+    /// Reason to disconnect the client, see [`Client::disconnect`]
+    disconnect: watch::Sender<Option<String>>,
     id: u32,
+    /// Changes sent to the client, in order, which it did not acknowledge yet
+    unacknowledged: VecDeque<PendingChange>,
+    /// Editor version of the last change received from the client, per document
+    versions: HashMap<Url, i32>,
+    /// Last known cursor and selections of the client
+    presence: Option<Presence>,
+    /// Name of the user, given in [`ClientMessage::Hello`]
+    name: Option<String>,
+    /// What the user may do, given in its first [`ClientMessage::Hello`], an observer until then
+    role: Role,
+    /// Documents open in the editor of the client
+    open: HashSet<Url>,
+}
+
+/// Identifies a client in the logs
+fn display_name(id: u32, name: Option<&str>) -> String {
+    match name {
+        Some(name) => format!("#{id} ({name})"),
+        None => format!("#{id}"),
+    }
+}
+
+impl Client {
+    fn who(&self) -> String {
+        display_name(self.id, self.name.as_deref())
+    }
+
+    /// Describes the client to its peers
+    fn peer(&self) -> Peer {
+        let mut open: Vec<_> = self.open.iter().cloned().collect();
+        open.sort();
+        Peer {
+            id: self.id,
+            name: self.name.clone(),
+            role: self.role,
+            open,
+        }
+    }
+
+    /// Records the editor version of a change made by this client.
+    /// Returns false if it is not newer than the previous change, which means that it was
+    /// duplicated or reordered.
+    fn record_version(&mut self, uri: &Url, version: i32) -> bool {
+        match self.versions.get_mut(uri) {
+            Some(last) if *last >= version => false,
+            Some(last) => {
+                *last = version;
+                true
+            }
+            None => {
+                self.versions.insert(uri.clone(), version);
+                true
+            }
+        }
+    }
+
+    /// Queues a message for the socket of the client
+    fn post(&mut self, msg: tungstenite::Message) -> anyhow::Result<()> {
+        match self.outgoing.try_send(msg) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                self.disconnect("too many messages are waiting to be sent");
+                bail!("the client does not keep up")
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => bail!("the connection is closed"),
+        }
+    }
+
+    /// Sends a message, keeping track of the changes until the client acknowledges them
+    fn send(&mut self, msg: &ServerMessage) -> anyhow::Result<()> {
+        let ws_msg = ws_message(msg);
+        if let ServerMessage::Common(common) = msg {
+            if self.unacknowledged.len() >= MAX_UNACKNOWLEDGED {
+                self.disconnect("too many changes are not acknowledged");
+                bail!("the client does not keep up");
+            }
+            self.unacknowledged.push_back(PendingChange {
+                id: common.id(),
+                msg: ws_msg.clone(),
+                sent_at: Instant::now(),
+                resent: 0,
+            });
+        }
+        self.post(ws_msg)
+    }
+
+    /// Closes the connection of a client which fell too far behind, it restores the documents
+    /// from a snapshot when it connects again
+    fn disconnect(&self, reason: &str) {
+        warn!("{}: disconnecting: {reason}", self.who());
+        self.disconnect.send_replace(Some(reason.to_owned()));
+    }
+
+    /// Marks a change as delivered
+    fn acknowledge(&mut self, id: Uuid) {
+        match self
+            .unacknowledged
+            .iter()
+            .position(|change| change.id == id)
+        {
+            Some(index) => {
+                self.unacknowledged.remove(index);
+                debug!("{}: change {id} delivered", self.who());
+            }
+            None => warn!("{}: acknowledged unknown change {id}", self.who()),
+        }
+    }
+
+    /// Sends again the changes which were not acknowledged in time, disconnecting the client if
+    /// they were sent too many times already
+    fn resend_late_changes(&mut self) -> anyhow::Result<()> {
+        let now = Instant::now();
+        let late = self
+            .unacknowledged
+            .iter()
+            .filter(|change| now - change.sent_at >= ACK_TIMEOUT)
+            .count();
+        if late == 0 {
+            return Ok(());
+        }
+        let who = self.who();
+        warn!(
+            "{who}: falling behind, {late} changes were not acknowledged after {ACK_TIMEOUT:?} ({} pending), sending them again",
+            self.unacknowledged.len()
+        );
+        if let Some(change) = self
+            .unacknowledged
+            .iter()
+            .find(|change| change.resent >= MAX_RESENDS && now - change.sent_at >= ACK_TIMEOUT)
+        {
+            let reason = format!(
+                "change {} was sent {} times without being acknowledged",
+                change.id,
+                MAX_RESENDS + 1
+            );
+            self.disconnect(&reason);
+            bail!("the client does not acknowledge its changes");
+        }
+        let mut resent = Vec::new();
+        for change in self
+            .unacknowledged
+            .iter_mut()
+            .filter(|change| now - change.sent_at >= ACK_TIMEOUT)
+        {
+            change.sent_at = now;
+            change.resent += 1;
+            if change.resent > 1 {
+                warn!(
+                    "{who}: change {} sent again {} times",
+                    change.id, change.resent
+                );
+            }
+            resent.push(change.msg.clone());
+        }
+        for msg in resent {
+            self.post(msg)?;
+        }
+        Ok(())
+    }
+}
+
+enum SyncDocument {
+    Ot(ot::Document),
+    Crdt(Box<crdt::Document>),
+}
+
+/// A document shared by the clients
+struct Document {
+    /// Source of truth for the content of the document
+    buffer: Buffer,
+    sync: SyncDocument,
+    history: History,
+}
+
+impl Document {
+    fn new(engine: SyncEngine) -> Self {
+        let sync = match engine {
+            SyncEngine::Ot => SyncDocument::Ot(ot::Document::default()),
+            SyncEngine::Crdt => SyncDocument::Crdt(Box::default()),
+        };
+        Self {
+            buffer: Buffer::default(),
+            sync,
+            history: History::default(),
+        }
+    }
+
+    /// Applies a change made by `author` on top of `revision`, see [`ot::Document::apply`].
+    /// Returns the rebased content changes and the resulting revision.
+    fn apply_ot_change(
+        &mut self,
+        author: Option<String>,
+        revision: Revision,
+        changes: &[TextDocumentContentChangeEvent],
+    ) -> anyhow::Result<(Vec<TextDocumentContentChangeEvent>, Revision)> {
+        let SyncDocument::Ot(ot) = &mut self.sync else {
+            bail!("the server uses crdt changes");
+        };
+        let before = self.buffer.to_string();
+        let changes = ot.apply(&mut self.buffer, revision, changes)?;
+        self.history.record(&before, author, changes.clone())?;
+        Ok((changes, ot.revision()))
+    }
+
+    /// Restores a document saved with [`Self::store`]
+    fn restore(engine: SyncEngine, stored: StoredDocument) -> anyhow::Result<Self> {
+        let sync = match engine {
+            SyncEngine::Ot => SyncDocument::Ot(ot::Document::at_revision(stored.revision)),
+            SyncEngine::Crdt => {
+                let crdt = stored.crdt.context("the document has no crdt content")?;
+                SyncDocument::Crdt(Box::new(crdt::Document::load(&crdt)?))
+            }
+        };
+        Ok(Self {
+            buffer: Buffer::new(&stored.text),
+            sync,
+            history: History::starting_at(stored.revision),
+        })
+    }
+
+    fn store(&mut self, uri: Url) -> StoredDocument {
+        let Snapshot {
+            uri, text, crdt, ..
+        } = self.snapshot(uri);
+        StoredDocument {
+            uri,
+            text,
+            revision: self.history.revision(),
+            crdt,
+        }
+    }
+
+    fn snapshot(&mut self, uri: Url) -> Snapshot {
+        let (revision, crdt) = match &mut self.sync {
+            SyncDocument::Ot(ot) => (ot.revision(), None),
+            SyncDocument::Crdt(crdt) => (0, Some(crdt.save())),
+        };
+        Snapshot {
+            uri,
+            revision,
+            text: self.buffer.to_string(),
+            crdt,
+        }
+    }
+
+    /// Compares a checksum sent by a client with the content of the document in the same state.
+    /// Returns `None` if the server did not receive the changes leading to this state yet.
+    fn matches(&mut self, checksum: &Checksum) -> Option<bool> {
+        let text = match &mut self.sync {
+            SyncDocument::Ot(ot) => ot.text_at(&self.buffer, checksum.revision).ok()?,
+            SyncDocument::Crdt(crdt) => crdt.text_at(checksum.crdt_heads.as_deref()?)?,
+        };
+        Some(Checksum::hash(&text) == checksum.hash)
+    }
+
+    /// Moves the positions of a presence through the changes applied since its revision.
+    /// With crdt, clients move the positions through the changes they receive themselves.
+    fn transform_presence(&self, presence: &mut Presence) -> anyhow::Result<()> {
+        let SyncDocument::Ot(ot) = &self.sync else {
+            return Ok(());
+        };
+        let revision = presence.revision;
+        map_positions(presence, |positions| {
+            ot.transform_positions(&self.buffer, revision, positions)
+        })?;
+        presence.revision = ot.revision();
+        Ok(())
+    }
+
+    fn apply_crdt_change(&mut self, author: Option<String>, changes: &[u8]) -> anyhow::Result<()> {
+        let SyncDocument::Crdt(crdt) = &mut self.sync else {
+            bail!("the server uses ot changes");
+        };
+        let before = self.buffer.to_string();
+        let changes = crdt.remote_change(changes)?;
+        self.buffer.apply_changes(&changes);
+        self.history.record(&before, author, changes)
+    }
+}
+
+/// Documents shared by the clients, kept in sync with the [`SyncEngine`] in use
+struct Documents {
+    engine: SyncEngine,
+    by_uri: HashMap<Url, Document>,
+    /// Where the edits are saved, if the server has a storage directory, see [`write_storage`]
+    storage: Option<mpsc::UnboundedSender<StorageWrite>>,
+    /// Number of edits logged since the last snapshot
+    logged: usize,
+}
+
+impl Documents {
+    fn new(engine: SyncEngine, storage: Option<SessionStorage>) -> Self {
+        Self {
+            engine,
+            by_uri: HashMap::new(),
+            storage: storage.map(spawn_storage_writer),
+            logged: 0,
+        }
+    }
+
+    /// Loads the documents of a session from its snapshot and the edits logged after it
+    fn restore(engine: SyncEngine, storage: SessionStorage) -> anyhow::Result<Self> {
+        let (snapshot, log) = storage.load()?;
+        let mut by_uri = HashMap::new();
+        if let Some(snapshot) = snapshot {
+            if snapshot.engine != engine {
+                bail!(
+                    "the session was saved with {:?}, not {engine:?}",
+                    snapshot.engine
+                );
+            }
+            for stored in snapshot.documents {
+                let uri = stored.uri.clone();
+                let document = Document::restore(engine, stored)
+                    .with_context(|| format!("Failed to restore {uri}"))?;
+                by_uri.insert(uri, document);
+            }
+        }
+        let mut documents = Self {
+            engine,
+            by_uri,
+            storage: None,
+            logged: 0,
+        };
+        for LogEntry { uri, edit, crdt } in log {
+            let document = documents.get_or_create(uri.clone());
+            if edit.revision <= document.history.revision() {
+                // already in the snapshot
+                continue;
+            }
+            match crdt {
+                Some(crdt) => document.apply_crdt_change(edit.author, &crdt),
+                None => {
+                    let revision = document.history.revision();
+                    document
+                        .apply_ot_change(edit.author, revision, &edit.changes)
+                        .map(|_| ())
+                }
+            }
+            .with_context(|| format!("Failed to replay edit {} of {uri}", edit.revision))?;
+        }
+        documents.storage = Some(spawn_storage_writer(storage));
+        documents.save();
+        Ok(documents)
+    }
+
+    /// Replaces the snapshot of the session with the current documents
+    fn save(&mut self) {
+        if self.storage.is_none() {
+            return;
+        }
+        let snapshot = SessionSnapshot {
+            engine: self.engine,
+            documents: self
+                .by_uri
+                .iter_mut()
+                .map(|(uri, document)| document.store(uri.clone()))
+                .collect(),
+        };
+        self.logged = 0;
+        self.write(StorageWrite::Save(snapshot));
+    }
+
+    /// Logs the last edit applied to `uri`, `crdt` being its encoded Automerge changes
+    fn persist(&mut self, uri: &Url, crdt: Option<Vec<u8>>) {
+        let (Some(_), Some(edit)) = (
+            &self.storage,
+            self.by_uri
+                .get(uri)
+                .and_then(|document| document.history.last()),
+        ) else {
+            return;
+        };
+        let entry = LogEntry {
+            uri: uri.clone(),
+            edit: edit.clone(),
+            crdt,
+        };
+        self.write(StorageWrite::Append(entry));
+        self.logged += 1;
+        if self.logged >= SNAPSHOT_INTERVAL {
+            self.save();
+        }
+    }
+
+    fn write(&self, write: StorageWrite) {
+        if let Some(storage) = &self.storage
+            && storage.send(write).is_err()
+        {
+            error!("The storage writer stopped, the session is not saved anymore");
+        }
+    }
+
+    fn get_or_create(&mut self, uri: Url) -> &mut Document {
+        let engine = self.engine;
+        self.by_uri
+            .entry(uri)
+            .or_insert_with(|| Document::new(engine))
+    }
+
+    fn snapshot(&mut self) -> Vec<Snapshot> {
+        self.by_uri
+            .iter_mut()
+            .map(|(uri, document)| document.snapshot(uri.clone()))
+            .collect()
+    }
+
+    /// Applies a change made by `author` and logs it.
+    /// Returns the change to broadcast, and with ot the revision to acknowledge.
+    fn apply(
+        &mut self,
+        author: Option<String>,
+        change: CommonMessage,
+    ) -> anyhow::Result<(CommonMessage, Option<Revision>)> {
+        match change {
+            CommonMessage::Change(change) => {
+                let uri = change.change.text_document.uri.clone();
+                let (content_changes, revision) = self.get_or_create(uri.clone()).apply_ot_change(
+                    author.clone(),
+                    change.revision,
+                    &change.change.content_changes,
+                )?;
+                self.persist(&uri, None);
+                let change = Change {
+                    id: change.id,
+                    author,
+                    revision,
+                    change: DidChangeTextDocumentParams {
+                        text_document: change.change.text_document,
+                        content_changes,
+                    },
+                };
+                Ok((CommonMessage::Change(change), Some(revision)))
+            }
+            CommonMessage::CrdtChange(mut change) => {
+                change.author = author;
+                self.get_or_create(change.uri.clone())
+                    .apply_crdt_change(change.author.clone(), &change.changes)?;
+                self.persist(&change.uri, Some(change.changes.clone()));
+                Ok((CommonMessage::CrdtChange(change), None))
+            }
+        }
+    }
+
+    /// Rebases a presence on the current revision of its document
+    fn transform_presence(&self, mut presence: Presence) -> anyhow::Result<Presence> {
+        if let Some(document) = self.by_uri.get(&presence.uri) {
+            document.transform_presence(&mut presence)?;
+        }
+        Ok(presence)
+    }
+
+    /// Presences of the clients other than the one at `to`, rebased on the current revisions
+    fn presences(&self, clients: &HashMap<String, Client>, to: &str) -> Vec<ServerMessage> {
+        clients
+            .iter()
+            .filter(|(addr, _)| addr.as_str() != to)
+            .filter_map(|(_, client)| client.presence.clone())
+            .filter_map(|presence| self.transform_presence(presence).ok())
+            .map(ServerMessage::Presence)
+            .collect()
+    }
+}
+
+/// Clients editing the same documents
+#[derive(Clone)]
+struct Session {
+    clients: Arc<Mutex<HashMap<String, Client>>>,
+    documents: Arc<Mutex<Documents>>,
+}
+
+/// Written to the storage of a session by [`write_storage`]
+enum StorageWrite {
+    Append(LogEntry),
+    Save(SessionSnapshot),
+}
+
+fn spawn_storage_writer(storage: SessionStorage) -> mpsc::UnboundedSender<StorageWrite> {
+    let (writes, receiver) = mpsc::unbounded_channel();
+    tokio::task::spawn_blocking(move || write_storage(storage, receiver));
+    writes
+}
+
+/// Writes to the disk in order, away from the runtime so that the clients are not held up
+fn write_storage(mut storage: SessionStorage, mut writes: mpsc::UnboundedReceiver<StorageWrite>) {
+    while let Some(write) = writes.blocking_recv() {
+        match write {
+            StorageWrite::Append(entry) => {
+                if let Err(err) = storage.append(&entry) {
+                    error!(
+                        "Failed to log edit {} of {}: {err:#}",
+                        entry.edit.revision, entry.uri
+                    );
+                }
+            }
+            StorageWrite::Save(snapshot) => {
+                if let Err(err) = storage.save(&snapshot) {
+                    error!("Failed to save snapshot: {err:#}");
+                }
+            }
+        }
+    }
+}
+
+/// Sessions by name, created when a client first joins them
+struct Sessions {
+    engine: SyncEngine,
+    by_name: HashMap<String, Session>,
+    storage: Option<Storage>,
+}
+
+impl Sessions {
+    fn new(engine: SyncEngine, storage: Option<Storage>) -> Self {
+        Self {
+            engine,
+            by_name: HashMap::new(),
+            storage,
+        }
+    }
+
+    /// Restores the sessions saved in the storage directory
+    fn load(&mut self) -> anyhow::Result<()> {
+        let Some(storage) = &self.storage else {
+            return Ok(());
+        };
+        for name in storage.sessions()? {
+            let documents = Documents::restore(self.engine, storage.open(&name)?)
+                .with_context(|| format!("Failed to restore session {name}"))?;
+            info!(
+                "Restored session {name} with {} documents",
+                documents.by_uri.len()
+            );
+            self.by_name.insert(
+                name,
+                Session {
+                    clients: Arc::default(),
+                    documents: Arc::new(Mutex::new(documents)),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn get_or_create(&mut self, name: &str) -> Session {
+        let engine = self.engine;
+        let storage = &self.storage;
+        self.by_name
+            .entry(name.to_owned())
+            .or_insert_with(|| {
+                info!("Creating session {name}");
+                let storage = storage.as_ref().and_then(|storage| {
+                    storage
+                        .open(name)
+                        .inspect_err(|err| error!("Session {name} won't be saved: {err:#}"))
+                        .ok()
+                });
+                Session {
+                    clients: Arc::default(),
+                    documents: Arc::new(Mutex::new(Documents::new(engine, storage))),
+                }
+            })
+            .clone()
+    }
+}
+
+/// Name of the session selected by the path of the websocket URL, `/my-team` for `my-team`
+fn session_name(path: &str) -> String {
+    match path.trim_matches('/') {
+        "" => DEFAULT_SESSION.to_owned(),
+        name => name.to_owned(),
+    }
+}
+
+fn tls_acceptor(tls: &Tls) -> anyhow::Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(&tls.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read certificates from {}", tls.cert.display()))?;
+    let key = PrivateKeyDer::from_pem_file(&tls.key)
+        .with_context(|| format!("Failed to read private key from {}", tls.key.display()))?;
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid TLS certificate or key")?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Relays the changes between the editors of each session
+#[derive(Parser)]
+struct Args {
+    /// How documents are kept in sync between clients
+    #[arg(long, value_enum, default_value_t)]
+    engine: SyncEngine,
+    /// TOML file with the settings below, the command line takes precedence over it
+    #[arg(long)]
+    config: Option<PathBuf>,
+    #[command(flatten)]
+    settings: ServerConfig,
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
-    init_logger();
+    let args = Args::parse();
+    let settings = match &args.config {
+        Some(path) => args.settings.clone().or(ServerConfig::load(path)?),
+        None => args.settings.clone(),
+    };
+    init_logger_with_level(settings.log_level());
 
-    info!("Listening at ws://{LISTEN_ADDR}");
-    let listener = TcpListener::bind(LISTEN_ADDR)
+    let tls = settings.tls()?.as_ref().map(tls_acceptor).transpose()?;
+    let listen_addr = settings.listen_addr();
+    let scheme = if tls.is_some() { "wss" } else { "ws" };
+    let listener = TcpListener::bind(listen_addr)
         .await
-        .with_context(|| format!("Failed to bind at addr {LISTEN_ADDR}"))?;
+        .with_context(|| format!("Failed to bind at addr {listen_addr}"))?;
+    info!("Listening at {scheme}://{listen_addr}");
+    if tls.is_none() && (settings.token.is_some() || !settings.users.is_empty()) {
+        warn!("Tokens are sent in clear text, use TLS to keep them secret");
+    }
+
+    let storage = settings.storage.as_deref().map(Storage::new).transpose()?;
+    let mut sessions = Sessions::new(args.engine, storage);
+    sessions.load()?;
+    let sessions = Arc::new(Mutex::new(sessions));
+    let recorder = match &settings.record {
+        Some(path) => Some(spawn_recorder(Recorder::new(path)?)),
+        None => None,
+    };
 
-    let clients = Arc::new(Mutex::new(HashMap::new()));
+    tokio::spawn({
+        let sessions = sessions.clone();
+        async move {
+            let mut interval = tokio::time::interval(ACK_TIMEOUT);
+            loop {
+                interval.tick().await;
+                let sessions: Vec<_> = sessions.lock().await.by_name.values().cloned().collect();
+                for session in sessions {
+                    for client in session.clients.lock().await.values_mut() {
+                        if let Err(err) = client.resend_late_changes() {
+                            error!("{}: failed to send changes again: {err:#}", client.who());
+                        }
+                    }
+                }
+            }
+        }
+    });
 
+    let settings = Arc::new(settings);
     let mut id_incr = 0;
     let mut next_id = || {
         id_incr += 1;
@@ -41,76 +739,591 @@ async fn main() -> anyhow::Result<()> {
     while let Ok((stream, peer_addr)) = listener.accept().await {
         let peer_addr = peer_addr.to_string();
         info!("Client connected: {peer_addr}");
-        let ws = match tokio_tungstenite::accept_async(stream).await {
-            Ok(ws) => ws,
-            Err(err) => {
-                error!("Error in websocket handshake: {err:#}");
-                break;
-            }
-        };
-        let (send, mut recv) = ws.split();
-        let clients = clients.clone();
+        // changes are small messages which should be sent right away
+        if let Err(err) = stream.set_nodelay(true) {
+            warn!("Failed to disable Nagle's algorithm for {peer_addr}: {err:#}");
+        }
         let client_id = next_id();
-        clients.lock().await.insert(
-            peer_addr.clone(),
-            Client {
-                send,
-                id: client_id,
-            },
-        );
+        let tls = tls.clone();
+        let settings = settings.clone();
+        let sessions = sessions.clone();
+        let recorder = recorder.clone();
+        let engine = args.engine;
+        // a client stalling mid-handshake only holds up its own task
         tokio::spawn(async move {
-            while let Ok(Some(msg)) = recv
-                .try_next()
+            let (ws, path, token) =
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, accept(stream, tls.as_ref())).await {
+                    Ok(Ok(accepted)) => accepted,
+                    Ok(Err(err)) => {
+                        error!("Error in handshake with {peer_addr}: {err:#}");
+                        return;
+                    }
+                    Err(_) => {
+                        warn!("Handshake with {peer_addr} timed out");
+                        return;
+                    }
+                };
+            let (mut send, mut recv) = ws.split();
+            let user = match settings.authenticate(token.as_deref()) {
+                Ok(user) => user,
+                Err(err) => {
+                    warn!("Rejecting client {peer_addr}: {err:#}");
+                    let close = tungstenite::Message::Close(Some(CloseFrame {
+                        code: CloseCode::Policy,
+                        reason: format!("{err:#}").into(),
+                    }));
+                    if let Err(err) = send.send(close).await {
+                        error!("Failed to close the connection of {peer_addr}: {err:#}");
+                    }
+                    return;
+                }
+            };
+            let mut session_name = session_name(&path);
+            if let Err(err) = send
+                .send(ws_message(&ServerMessage::SyncEngine(engine)))
                 .await
-                .inspect_err(|_| info!("Client disconnected: {peer_addr}"))
             {
+                error!("Failed to greet client {peer_addr}: {err:#}");
+                return;
+            }
+            let Session {
+                mut clients,
+                mut documents,
+            } = sessions.lock().await.get_or_create(&session_name);
+            let mut who = display_name(client_id, user.as_deref());
+            info!("{who}: joined session {session_name}");
+            let (outgoing, queue) = mpsc::channel(OUTGOING_CAPACITY);
+            let (disconnect, mut disconnected) = watch::channel(None);
+            tokio::spawn(write_messages(send, queue, disconnect.subscribe()));
+            clients.lock().await.insert(
+                peer_addr.clone(),
+                Client {
+                    outgoing,
+                    disconnect,
+                    id: client_id,
+                    unacknowledged: VecDeque::new(),
+                    versions: HashMap::new(),
+                    presence: None,
+                    name: user.clone(),
+                    role: Role::Observer,
+                    open: HashSet::new(),
+                },
+            );
+            // clients may only edit once they said hello
+            let mut role = Role::Observer;
+            let mut greeted = false;
+            loop {
+                let msg = tokio::select! {
+                    msg = recv.try_next() => msg,
+                    // the writer closes the connection
+                    _ = disconnected.changed() => break,
+                };
+                let Ok(Some(msg)) = msg.inspect_err(|_| info!("Client disconnected: {peer_addr}"))
+                else {
+                    break;
+                };
                 // info!("received msg: {msg:#?}");
-                let msg: ClientMessage =
-                    serde_json::from_str(&msg.into_text().expect("Client sent a non text message"))
-                        .expect("Client sent an invalid message");
+                if msg.is_close() {
+                    info!("Client disconnected: {peer_addr}");
+                    break;
+                }
+                // pings are answered by tungstenite, the messages are all text
+                let tungstenite::Message::Text(text) = msg else {
+                    continue;
+                };
+                let msg: ClientMessage = match serde_json::from_str(&text) {
+                    Ok(msg) => msg,
+                    Err(err) => {
+                        warn!("{who}: ignoring an invalid message: {err:#}");
+                        continue;
+                    }
+                };
                 match msg {
-                    ClientMessage::AcknowledgeChange(_uuid) => todo!(),
-                    ClientMessage::Common(common_message) => {
-                        match &common_message {
-                            codlab::messages::CommonMessage::Change(change) => {
-                                let change = &change.change.content_changes[0];
-                                if let Some(range) = change.range {
+                    ClientMessage::Hello {
+                        username,
+                        role: hello_role,
+                    } => {
+                        if greeted {
+                            warn!("{who}: ignoring a second hello, the role can't change");
+                            continue;
+                        }
+                        greeted = true;
+                        role = hello_role;
+                        if role == Role::Observer {
+                            info!("{who}: is an observer");
+                        }
+                        if let Some(client) = clients.lock().await.get_mut(&peer_addr) {
+                            client.role = role;
+                        }
+                        match username {
+                            Some(username) if user.is_some() => warn!(
+                                "{who}: ignoring the name {username}, the token belongs to this user"
+                            ),
+                            // changes are attributed to the users by their token only
+                            Some(username) if settings.users.contains_key(&username) => warn!(
+                                "{who}: ignoring the name {username}, the token of this user was not given"
+                            ),
+                            Some(username) => {
+                                info!("{who}: is {username}");
+                                who = display_name(client_id, Some(&username));
+                                if let Some(client) = clients.lock().await.get_mut(&peer_addr) {
+                                    client.name = Some(username);
+                                }
+                            }
+                            None => {}
+                        }
+                    }
+                    ClientMessage::JoinSession(name) => {
+                        let Some(mut client) = clients.lock().await.remove(&peer_addr) else {
+                            continue;
+                        };
+                        // the changes of the previous session don't matter anymore
+                        client.unacknowledged.clear();
+                        client.versions.clear();
+                        client.open.clear();
+                        if let Some(presence) = client.presence.take() {
+                            let msg = ServerMessage::UserLeft(presence.user);
+                            broadcast(&mut *clients.lock().await, &peer_addr, &msg);
+                        }
+                        Session { clients, documents } = sessions.lock().await.get_or_create(&name);
+                        session_name = name.clone();
+                        info!("{who}: joined session {name}");
+                        // keep the documents locked until the client is added, so that the
+                        // snapshot is followed by exactly the changes applied after it
+                        let mut documents = documents.lock().await;
+                        let mut lock = clients.lock().await;
+                        let msgs = [
+                            ServerMessage::Joined(name),
+                            ServerMessage::Snapshot(documents.snapshot()),
+                        ]
+                        .into_iter()
+                        .chain(documents.presences(&lock, &peer_addr));
+                        for msg in msgs {
+                            if let Err(err) = client.post(ws_message(&msg)) {
+                                error!("{who}: failed to send session: {err:#}");
+                            }
+                        }
+                        lock.insert(peer_addr.clone(), client);
+                    }
+                    ClientMessage::AcknowledgeChange(id) => {
+                        if let Some(client) = clients.lock().await.get_mut(&peer_addr) {
+                            client.acknowledge(id);
+                        }
+                    }
+                    ClientMessage::RequestSnapshot => {
+                        // keep the documents locked until the snapshot is sent, so that it is
+                        // followed by exactly the changes applied after it
+                        let mut documents = documents.lock().await;
+                        let mut lock = clients.lock().await;
+                        let msgs: Vec<_> =
+                            std::iter::once(ServerMessage::Snapshot(documents.snapshot()))
+                                .chain(documents.presences(&lock, &peer_addr))
+                                .collect();
+                        if let Some(client) = lock.get_mut(&peer_addr) {
+                            for msg in msgs {
+                                if let Err(err) = client.post(ws_message(&msg)) {
+                                    error!("{who}: failed to send snapshot: {err:#}");
+                                }
+                            }
+                        }
+                    }
+                    ClientMessage::Presence(presence) => {
+                        // keep the documents locked until the presence is broadcasted, so that
+                        // peers receive it after the changes it was rebased on
+                        let documents = documents.lock().await;
+                        let presence = match documents.transform_presence(presence) {
+                            Ok(presence) => presence,
+                            Err(err) => {
+                                warn!("{who}: dropping presence: {err:#}");
+                                continue;
+                            }
+                        };
+                        let mut lock = clients.lock().await;
+                        let Some(client) = lock.get_mut(&peer_addr) else {
+                            continue;
+                        };
+                        let presence = Presence {
+                            username: client.name.clone(),
+                            ..presence
+                        };
+                        client.presence = Some(presence.clone());
+                        broadcast(&mut lock, &peer_addr, &ServerMessage::Presence(presence));
+                    }
+                    ClientMessage::Checksums(checksums) => {
+                        // keep the documents locked until the corrections are sent, so that they
+                        // are followed by exactly the changes applied after them
+                        let mut documents = documents.lock().await;
+                        let corrections: Vec<_> = checksums
+                            .into_iter()
+                            .filter_map(|checksum| {
+                                let document = documents.by_uri.get_mut(&checksum.uri)?;
+                                if document.matches(&checksum)? {
+                                    return None;
+                                }
+                                warn!(
+                                    "{who}: {} is out of sync at revision {}, sending a correction",
+                                    checksum.uri, checksum.revision
+                                );
+                                Some(document.snapshot(checksum.uri))
+                            })
+                            .collect();
+                        if let Some(client) = clients.lock().await.get_mut(&peer_addr) {
+                            for snapshot in corrections {
+                                let msg = ServerMessage::Correction(snapshot);
+                                if let Err(err) = client.post(ws_message(&msg)) {
+                                    error!("{who}: failed to send correction: {err:#}");
+                                }
+                            }
+                        }
+                    }
+                    ClientMessage::RequestHistory { uri, range } => {
+                        let edits = documents
+                            .lock()
+                            .await
+                            .by_uri
+                            .get(&uri)
+                            .map(|document| document.history.edits(&document.buffer, range))
+                            .unwrap_or_default();
+                        let msg = ServerMessage::History(EditHistory { uri, edits });
+                        if let Some(client) = clients.lock().await.get_mut(&peer_addr)
+                            && let Err(err) = client.post(ws_message(&msg))
+                        {
+                            error!("{who}: failed to send history: {err:#}");
+                        }
+                    }
+                    ClientMessage::ListPeers => {
+                        let mut lock = clients.lock().await;
+                        let mut peers: Vec<_> = lock
+                            .iter()
+                            .filter(|(addr, _)| **addr != peer_addr)
+                            .map(|(_, client)| client.peer())
+                            .collect();
+                        peers.sort_by_key(|peer| peer.id);
+                        if let Some(client) = lock.get_mut(&peer_addr)
+                            && let Err(err) = client.post(ws_message(&ServerMessage::Peers(peers)))
+                        {
+                            error!("{who}: failed to send peers: {err:#}");
+                        }
+                    }
+                    ClientMessage::OpenDocument { uri, content } => {
+                        // keep the documents locked until the change is broadcasted, so that
+                        // peers receive the changes in the order of their revisions
+                        let mut documents = documents.lock().await;
+                        let mut lock = clients.lock().await;
+                        let Some(client) = lock.get_mut(&peer_addr) else {
+                            continue;
+                        };
+                        client.open.insert(uri.clone());
+                        // editors count the versions from the one they open the document with
+                        client.versions.remove(&uri);
+                        let Some(change) = content else {
+                            info!("{who}: opened {uri}");
+                            continue;
+                        };
+                        if role == Role::Observer {
+                            warn!("{who}: not sharing {uri}, observers can't edit");
+                            continue;
+                        }
+                        if documents.by_uri.contains_key(&uri) {
+                            // opened by a peer too, or before the client received the snapshot
+                            info!("{who}: {uri} is already shared, sending its content");
+                            let snapshot = documents.get_or_create(uri.clone()).snapshot(uri);
+                            let msg = ServerMessage::Correction(snapshot);
+                            if let Err(err) = client.post(ws_message(&msg)) {
+                                error!("{who}: failed to send correction: {err:#}");
+                            }
+                            continue;
+                        }
+                        if let CommonMessage::Change(change) = &change {
+                            client.record_version(&uri, change.change.text_document.version);
+                        }
+                        let author = client.name.clone();
+                        let id = change.id();
+                        let (change, revision) = match documents.apply(author.clone(), change) {
+                            Ok(applied) => applied,
+                            Err(err) => {
+                                warn!("{who}: failed to open {uri}: {err:#}");
+                                documents.by_uri.remove(&uri);
+                                continue;
+                            }
+                        };
+                        info!("{who}: opened and shared {uri}");
+                        record(
+                            recorder.as_ref(),
+                            &session_name,
+                            &ServerMessage::Common(change),
+                        );
+                        let snapshot = documents.get_or_create(uri.clone()).snapshot(uri.clone());
+                        let msg = ServerMessage::DocumentOpened { author, snapshot };
+                        broadcast(&mut lock, &peer_addr, &msg);
+                        acknowledge(&mut lock, &peer_addr, uri, id, revision.unwrap_or_default());
+                    }
+                    ClientMessage::CloseDocument(uri) => {
+                        let mut lock = clients.lock().await;
+                        if let Some(client) = lock.get_mut(&peer_addr) {
+                            client.open.remove(&uri);
+                            client.versions.remove(&uri);
+                        }
+                        let readers: Vec<_> = lock
+                            .values()
+                            .filter(|client| client.open.contains(&uri))
+                            .map(Client::who)
+                            .collect();
+                        if readers.is_empty() {
+                            info!("{who}: closed {uri}, nobody has it open anymore");
+                        } else {
+                            info!("{who}: closed {uri}, still open by {}", readers.join(", "));
+                        }
+                    }
+                    ClientMessage::SaveDocument(uri) => {
+                        info!("{who}: saved {uri}");
+                        let mut lock = clients.lock().await;
+                        let author = lock.get(&peer_addr).and_then(|client| client.name.clone());
+                        let msg = ServerMessage::DocumentSaved(SavedDocument { uri, author });
+                        broadcast(&mut lock, &peer_addr, &msg);
+                    }
+                    ClientMessage::Common(common) if role == Role::Observer => {
+                        warn!(
+                            "{who}: dropping change {} on {}, observers can't edit",
+                            common.id(),
+                            common.uri()
+                        );
+                    }
+                    ClientMessage::Common(change) => {
+                        let id = change.id();
+                        let uri = change.uri().clone();
+                        match &change {
+                            CommonMessage::Change(change) => {
+                                let Some(content_change) = change.change.content_changes.first()
+                                else {
+                                    warn!(
+                                        "{who}: dropping change {id} on {uri}: it changes nothing"
+                                    );
+                                    correct(
+                                        &mut *documents.lock().await,
+                                        &mut *clients.lock().await,
+                                        &peer_addr,
+                                        uri,
+                                    );
+                                    continue;
+                                };
+                                if let Some(range) = content_change.range {
                                     debug!(
-                                        "#{}: ({}:{}):({}:{}) {:#?}",
-                                        client_id,
+                                        "{}: ({}:{}):({}:{}) {:#?}",
+                                        who,
                                         range.start.line,
                                         range.start.character,
                                         range.end.line,
                                         range.end.character,
-                                        change.text
+                                        content_change.text
                                     );
                                 } else {
-                                    debug!("#{}: {:#?}", client_id, change.text);
+                                    debug!("{}: {:#?}", who, content_change.text);
+                                }
+                                let version = change.change.text_document.version;
+                                let newer = clients
+                                    .lock()
+                                    .await
+                                    .get_mut(&peer_addr)
+                                    .is_none_or(|client| client.record_version(&uri, version));
+                                if !newer {
+                                    warn!(
+                                        "{who}: dropping change {id} on {uri}: version {version} is not newer than the previous change"
+                                    );
+                                    correct(
+                                        &mut *documents.lock().await,
+                                        &mut *clients.lock().await,
+                                        &peer_addr,
+                                        uri,
+                                    );
+                                    continue;
                                 }
                             }
+                            CommonMessage::CrdtChange(_) => {
+                                debug!("{}: crdt change on {}", who, uri);
+                            }
                         }
-                        let msg = ServerMessage::Common(common_message);
-                        debug!("Broadcasting message...!");
+                        // keep the documents locked until the change is broadcasted, so that peers
+                        // receive the changes in the order of their revisions
+                        let mut documents = documents.lock().await;
                         let mut lock = clients.lock().await;
-                        let futs: Vec<_> = lock
-                            .iter_mut()
-                            .filter(|(addr, _)| addr != &&peer_addr)
-                            .map(|(_, client)| {
-                                client.send.send(tungstenite::Message::Text(
-                                    serde_json::to_string(&msg)
-                                        .expect("To be able to construct a json")
-                                        .into(),
-                                ))
-                            })
-                            .collect();
-                        let peers = futs.len();
-                        join_all(futs).await;
-                        debug!("Broadcasted message to {} peers successfully!", peers);
+                        let author = lock.get(&peer_addr).and_then(|client| client.name.clone());
+                        let (change, revision) = match documents.apply(author, change) {
+                            Ok(applied) => applied,
+                            Err(err) => {
+                                warn!("{who}: dropping change {id}: {err:#}");
+                                continue;
+                            }
+                        };
+                        let msg = ServerMessage::Common(change);
+                        record(recorder.as_ref(), &session_name, &msg);
+                        broadcast(&mut lock, &peer_addr, &msg);
+                        if let Some(revision) = revision {
+                            acknowledge(&mut lock, &peer_addr, uri, id, revision);
+                        }
                     }
                 }
             }
-            clients.lock().await.remove(&peer_addr);
+            let mut lock = clients.lock().await;
+            if let Some(Client {
+                presence: Some(presence),
+                ..
+            }) = lock.remove(&peer_addr)
+            {
+                broadcast(
+                    &mut lock,
+                    &peer_addr,
+                    &ServerMessage::UserLeft(presence.user),
+                );
+            }
         });
     }
     Ok(())
 }
+
+/// Runs the TLS and websocket handshakes, returns the path requested by the client and the token
+/// it authenticates with
+async fn accept(
+    stream: TcpStream,
+    tls: Option<&TlsAcceptor>,
+) -> anyhow::Result<(WebSocketStream<Box<dyn Stream>>, String, Option<String>)> {
+    let stream: Box<dyn Stream> = match tls {
+        Some(tls) => Box::new(tls.accept(stream).await.context("TLS handshake failed")?),
+        None => Box::new(stream),
+    };
+    let mut path = String::new();
+    let mut token = None;
+    // the error type is imposed by tungstenite
+    #[allow(clippy::result_large_err)]
+    let ws = tokio_tungstenite::accept_hdr_async(stream, |request: &Request, response| {
+        path = request.uri().path().to_owned();
+        token = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::to_owned);
+        Ok(response)
+    })
+    .await
+    .context("Websocket handshake failed")?;
+    Ok((ws, path, token))
+}
+
+/// Appends a change relayed in `session` to the recording, if the server records them, see
+/// [`write_recording`]
+fn record(recorder: Option<&mpsc::UnboundedSender<String>>, session: &str, msg: &ServerMessage) {
+    let (Some(recorder), ServerMessage::Common(change)) = (recorder, msg) else {
+        return;
+    };
+    match recording::line(session, change) {
+        Ok(line) => {
+            if recorder.send(line).is_err() {
+                error!("The recorder stopped, the changes are not recorded anymore");
+            }
+        }
+        Err(err) => error!("Failed to record change {}: {err:#}", change.id()),
+    }
+}
+
+fn spawn_recorder(recorder: Recorder) -> mpsc::UnboundedSender<String> {
+    let (lines, receiver) = mpsc::unbounded_channel();
+    tokio::task::spawn_blocking(move || write_recording(recorder, receiver));
+    lines
+}
+
+/// Appends the recorded changes in order, away from the runtime like [`write_storage`]
+fn write_recording(mut recorder: Recorder, mut lines: mpsc::UnboundedReceiver<String>) {
+    while let Some(line) = lines.blocking_recv() {
+        if let Err(err) = recorder.append(&line) {
+            error!("{err:#}");
+        }
+    }
+}
+
+/// Confirms to the client at `to` that its change was applied, resulting in `revision`
+fn acknowledge(
+    clients: &mut HashMap<String, Client>,
+    to: &str,
+    uri: Url,
+    id: Uuid,
+    revision: Revision,
+) {
+    let Some(author) = clients.get_mut(to) else {
+        return;
+    };
+    let ack = ServerMessage::AcknowledgeChange { uri, id, revision };
+    if let Err(err) = author.post(ws_message(&ack)) {
+        error!("{}: failed to acknowledge change: {err:#}", author.who());
+    }
+}
+
+/// Sends the content of `uri` to the client at `to` whose change was dropped, which would wait
+/// for it to be acknowledged otherwise
+fn correct(documents: &mut Documents, clients: &mut HashMap<String, Client>, to: &str, uri: Url) {
+    let (Some(document), Some(author)) = (documents.by_uri.get_mut(&uri), clients.get_mut(to))
+    else {
+        return;
+    };
+    let msg = ServerMessage::Correction(document.snapshot(uri));
+    if let Err(err) = author.post(ws_message(&msg)) {
+        error!("{}: failed to send correction: {err:#}", author.who());
+    }
+}
+
+/// Sends `msg` to every client but the one at `from`
+fn broadcast(clients: &mut HashMap<String, Client>, from: &str, msg: &ServerMessage) {
+    debug!("Broadcasting message...!");
+    let mut peers = 0;
+    for (_, client) in clients.iter_mut().filter(|(addr, _)| addr.as_str() != from) {
+        peers += 1;
+        if let Err(err) = client.send(msg) {
+            error!("{}: failed to send message: {err:#}", client.who());
+        }
+    }
+    debug!("Broadcasted message to {} peers successfully!", peers);
+}
+
+/// Writes the messages queued for a client to its socket, until the client is disconnected
+async fn write_messages(
+    mut sink: WebSocketSink,
+    mut outgoing: mpsc::Receiver<tungstenite::Message>,
+    mut disconnect: watch::Receiver<Option<String>>,
+) {
+    loop {
+        let msg = tokio::select! {
+            msg = outgoing.recv() => msg,
+            _ = disconnect.changed() => {
+                let reason = disconnect.borrow().clone().unwrap_or_default();
+                Some(tungstenite::Message::Close(Some(CloseFrame {
+                    code: CloseCode::Again,
+                    reason: reason.into(),
+                })))
+            }
+        };
+        let Some(msg) = msg else {
+            break;
+        };
+        let closing = msg.is_close();
+        match tokio::time::timeout(WRITE_TIMEOUT, sink.send(msg)).await {
+            Ok(Ok(())) if !closing => {}
+            Ok(Ok(())) => break,
+            Ok(Err(err)) => {
+                debug!("Failed to write to the socket: {err:#}");
+                break;
+            }
+            Err(_) => {
+                warn!("The client did not read its socket for {WRITE_TIMEOUT:?}");
+                break;
+            }
+        }
+    }
+}
+
+fn ws_message(msg: &ServerMessage) -> tungstenite::Message {
+    tungstenite::Message::Text(
+        serde_json::to_string(msg)
+            .expect("To be able to construct a json")
+            .into(),
+    )
+}
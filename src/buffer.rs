@@ -0,0 +1,149 @@
+//! Rope backed text content of a document.
+
+use std::fmt;
+
+use async_lsp::lsp_types::{DidChangeTextDocumentParams, Position, TextDocumentContentChangeEvent};
+use ropey::Rope;
+
+use crate::encoding::PositionEncoding;
+
+#[derive(Debug, Default, Clone)]
+pub struct Buffer {
+    rope: Rope,
+}
+
+impl Buffer {
+    pub fn new(text: &str) -> Self {
+        Self {
+            rope: Rope::from_str(text),
+        }
+    }
+
+    pub fn len_chars(&self) -> usize {
+        self.rope.len_chars()
+    }
+
+    /// Converts an LSP position to a char index.
+    /// Positions past the end of a line or of the buffer are clamped, as the LSP spec asks.
+    pub fn position_to_char(&self, position: Position) -> usize {
+        let line = position.line as usize;
+        if line >= self.rope.len_lines() {
+            return self.rope.len_chars();
+        }
+        let line_start = self.rope.line_to_char(line);
+        let mut line_len = self.rope.line(line).len_chars();
+        if line + 1 < self.rope.len_lines() {
+            // don't count the line break
+            line_len -= 1;
+        }
+        line_start + line_len.min(position.character as usize)
+    }
+
+    /// Converts a char index to an LSP position.
+    pub fn char_to_position(&self, char_idx: usize) -> Position {
+        let char_idx = char_idx.min(self.rope.len_chars());
+        let line = self.rope.char_to_line(char_idx);
+        Position::new(
+            line as u32,
+            (char_idx - self.rope.line_to_char(line)) as u32,
+        )
+    }
+
+    /// Converts the character of a position from the encoding `from` to the encoding `to`
+    pub fn convert_position(
+        &self,
+        position: Position,
+        from: PositionEncoding,
+        to: PositionEncoding,
+    ) -> Position {
+        let Some(line) = self.rope.get_line(position.line as usize) else {
+            return position;
+        };
+        Position::new(
+            position.line,
+            from.convert(to, line.chars(), position.character),
+        )
+    }
+
+    /// Applies a single content change. A change without a range replaces the whole buffer.
+    pub fn apply_change(&mut self, change: &TextDocumentContentChangeEvent) {
+        let (start, end) = match change.range {
+            Some(range) => {
+                let start = self.position_to_char(range.start);
+                let end = self.position_to_char(range.end);
+                (start.min(end), start.max(end))
+            }
+            None => (0, self.rope.len_chars()),
+        };
+        self.rope.remove(start..end);
+        self.rope.insert(start, &change.text);
+    }
+
+    /// Applies content changes one after the other, as in a `didChange` notification.
+    pub fn apply_changes(&mut self, changes: &[TextDocumentContentChangeEvent]) {
+        for change in changes {
+            self.apply_change(change);
+        }
+    }
+
+    pub fn apply(&mut self, params: &DidChangeTextDocumentParams) {
+        self.apply_changes(&params.content_changes);
+    }
+}
+
+impl fmt::Display for Buffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.rope)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_lsp::lsp_types::{Position, Range, TextDocumentContentChangeEvent};
+    use pretty_assertions::assert_eq;
+
+    use super::Buffer;
+
+    fn change(range: Option<Range>, text: &str) -> TextDocumentContentChangeEvent {
+        TextDocumentContentChangeEvent {
+            range,
+            range_length: None,
+            text: text.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_apply_changes() {
+        let mut buffer = Buffer::new("hello\nworld\n");
+        buffer.apply_changes(&[
+            change(
+                Some(Range::new(Position::new(0, 5), Position::new(1, 0))),
+                " ",
+            ),
+            change(
+                Some(Range::new(Position::new(0, 11), Position::new(0, 11))),
+                "!",
+            ),
+            // past the end of the line
+            change(
+                Some(Range::new(Position::new(0, 42), Position::new(0, 42))),
+                "!",
+            ),
+        ]);
+        assert_eq!(buffer.to_string(), "hello world!!\n");
+
+        buffer.apply_change(&change(None, "replaced"));
+        assert_eq!(buffer.to_string(), "replaced");
+    }
+
+    #[test]
+    fn test_positions() {
+        let buffer = Buffer::new("ab\ncd\n");
+        assert_eq!(buffer.position_to_char(Position::new(0, 9)), 2);
+        assert_eq!(buffer.position_to_char(Position::new(1, 1)), 4);
+        assert_eq!(buffer.position_to_char(Position::new(2, 0)), 6);
+        assert_eq!(buffer.position_to_char(Position::new(7, 3)), 6);
+        assert_eq!(buffer.char_to_position(4), Position::new(1, 1));
+        assert_eq!(buffer.char_to_position(6), Position::new(2, 0));
+    }
+}
@@ -22,6 +22,8 @@ impl ChangeEvent {
     ///     change.content_changes[0].text.len() == 1 && change.content_changes[0].range(start == end)
     ///   )
     /// ```
+    /// Positions are counted in chars, as between the clients and the server (see
+    /// [`crate::encoding`]), so each char of the text moves the next one by one.
     pub fn split_into_units(&self) -> Vec<Self> {
         let mut unit_changes = vec![];
         for change in &self.change.content_changes {
@@ -1,10 +1,23 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use tracing::Level;
 
 pub fn init_logger() {
+    init_logger_with_level(Level::DEBUG);
+}
+
+pub fn init_logger_with_level(level: Level) {
     let ts = tracing_subscriber::fmt();
     #[cfg(test)]
-    let ts = ts.with_max_level(Level::DEBUG).pretty();
+    let ts = ts.with_max_level(level).pretty();
     #[cfg(not(test))]
-    let ts = ts.with_max_level(Level::DEBUG).with_ansi(false);
+    let ts = ts.with_max_level(level).with_ansi(false);
     ts.with_writer(std::io::stderr).init();
 }
+
+/// Milliseconds since the Unix epoch
+pub fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
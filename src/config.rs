@@ -0,0 +1,264 @@
+//! Settings of the server, given on the command line or in a TOML config file.
+//!
+//! ```toml
+//! bind = "127.0.0.1"
+//! port = 7575
+//! log-level = "info"
+//! tls-cert = "/var/lib/codlab/cert.pem"
+//! tls-key = "/var/lib/codlab/key.pem"
+//! storage = "/var/lib/codlab/sessions"
+//! record = "/var/lib/codlab/changes.jsonl"
+//! # shared by everyone
+//! token = "correct horse battery staple"
+//!
+//! # tokens of the users, whose changes are attached to their name
+//! [users]
+//! alice = "wonderland"
+//! ```
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, bail};
+use serde::Deserialize;
+use tracing::Level;
+
+pub const DEFAULT_BIND: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+pub const DEFAULT_PORT: u16 = 7575;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    #[default]
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => Level::ERROR,
+            LogLevel::Warn => Level::WARN,
+            LogLevel::Info => Level::INFO,
+            LogLevel::Debug => Level::DEBUG,
+            LogLevel::Trace => Level::TRACE,
+        }
+    }
+}
+
+/// Certificate chain and private key, in PEM files, used to serve `wss://`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Every setting is optional so that the command line can override the config file,
+/// the defaults are applied last
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, clap::Args)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ServerConfig {
+    /// Address to listen on [default: 0.0.0.0]
+    #[arg(long)]
+    pub bind: Option<IpAddr>,
+    /// Port to listen on [default: 7575]
+    #[arg(long, short)]
+    pub port: Option<u16>,
+    /// Maximum level of the logs [default: debug]
+    #[arg(long, value_enum)]
+    pub log_level: Option<LogLevel>,
+    /// Certificate chain (PEM) to serve wss:// instead of ws://, requires --tls-key
+    #[arg(long)]
+    pub tls_cert: Option<PathBuf>,
+    /// Private key (PEM) of the certificate, requires --tls-cert
+    #[arg(long)]
+    pub tls_key: Option<PathBuf>,
+    /// Secret the clients must give to connect, anyone can connect if neither a token nor users
+    /// are set
+    #[arg(long)]
+    pub token: Option<String>,
+    /// Tokens of the users, by name, only in the config file
+    #[arg(skip)]
+    pub users: HashMap<String, String>,
+    /// Directory where the sessions are saved to survive restarts, they are only kept in memory
+    /// if not set
+    #[arg(long)]
+    pub storage: Option<PathBuf>,
+    /// File where every change relayed is appended, to be replayed with codlab-replay
+    #[arg(long)]
+    pub record: Option<PathBuf>,
+}
+
+impl ServerConfig {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        toml::from_str(&content)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))
+    }
+
+    /// Fills the settings missing from `self` with the ones of `fallback`
+    pub fn or(self, fallback: Self) -> Self {
+        Self {
+            bind: self.bind.or(fallback.bind),
+            port: self.port.or(fallback.port),
+            log_level: self.log_level.or(fallback.log_level),
+            tls_cert: self.tls_cert.or(fallback.tls_cert),
+            tls_key: self.tls_key.or(fallback.tls_key),
+            token: self.token.or(fallback.token),
+            users: if self.users.is_empty() {
+                fallback.users
+            } else {
+                self.users
+            },
+            storage: self.storage.or(fallback.storage),
+            record: self.record.or(fallback.record),
+        }
+    }
+
+    pub fn listen_addr(&self) -> SocketAddr {
+        SocketAddr::new(
+            self.bind.unwrap_or(DEFAULT_BIND),
+            self.port.unwrap_or(DEFAULT_PORT),
+        )
+    }
+
+    pub fn log_level(&self) -> Level {
+        self.log_level.unwrap_or_default().into()
+    }
+
+    pub fn tls(&self) -> anyhow::Result<Option<Tls>> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Ok(Some(Tls {
+                cert: cert.clone(),
+                key: key.clone(),
+            })),
+            (None, None) => Ok(None),
+            (Some(_), None) => bail!("A TLS certificate was given without its key"),
+            (None, Some(_)) => bail!("A TLS key was given without its certificate"),
+        }
+    }
+
+    /// Checks the token given by a connecting client.
+    /// Returns the name of the user it belongs to, if it is not the shared token.
+    pub fn authenticate(&self, token: Option<&str>) -> anyhow::Result<Option<String>> {
+        if self.token.is_none() && self.users.is_empty() {
+            return Ok(None);
+        }
+        let Some(token) = token else {
+            bail!("a token is required to join this server");
+        };
+        if self
+            .token
+            .as_deref()
+            .is_some_and(|secret| constant_time_eq(secret, token))
+        {
+            return Ok(None);
+        }
+        match self
+            .users
+            .iter()
+            .find(|(_, secret)| constant_time_eq(secret, token))
+        {
+            Some((name, _)) => Ok(Some(name.clone())),
+            None => bail!("invalid token"),
+        }
+    }
+}
+
+/// Compares secrets without telling through timing how much of them matched
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    use pretty_assertions::assert_eq;
+    use tracing::Level;
+
+    use super::{LogLevel, ServerConfig, Tls};
+
+    #[test]
+    fn test_defaults() {
+        let config = ServerConfig::default();
+        assert_eq!(config.listen_addr(), "0.0.0.0:7575".parse().unwrap());
+        assert_eq!(config.log_level(), Level::DEBUG);
+        assert_eq!(config.tls().unwrap(), None);
+    }
+
+    #[test]
+    fn test_command_line_overrides_config_file() {
+        let file: ServerConfig = toml::from_str(
+            r#"
+            bind = "127.0.0.1"
+            port = 8000
+            log-level = "warn"
+            tls-cert = "cert.pem"
+            tls-key = "key.pem"
+            "#,
+        )
+        .unwrap();
+        let command_line = ServerConfig {
+            port: Some(9000),
+            ..Default::default()
+        };
+
+        let config = command_line.or(file);
+        assert_eq!(
+            config.listen_addr(),
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9000)
+        );
+        assert_eq!(config.log_level, Some(LogLevel::Warn));
+        assert_eq!(
+            config.tls().unwrap(),
+            Some(Tls {
+                cert: "cert.pem".into(),
+                key: "key.pem".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_authenticate() {
+        assert_eq!(ServerConfig::default().authenticate(None).unwrap(), None);
+
+        let config: ServerConfig = toml::from_str(
+            r#"
+            token = "shared"
+            [users]
+            alice = "wonderland"
+            "#,
+        )
+        .unwrap();
+        assert!(config.authenticate(None).is_err());
+        assert!(config.authenticate(Some("wrong")).is_err());
+        assert_eq!(config.authenticate(Some("shared")).unwrap(), None);
+        assert_eq!(
+            config.authenticate(Some("wonderland")).unwrap().as_deref(),
+            Some("alice")
+        );
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(toml::from_str::<ServerConfig>("prot = 8000").is_err());
+
+        let config = ServerConfig {
+            tls_cert: Some("cert.pem".into()),
+            ..Default::default()
+        };
+        assert!(config.tls().is_err());
+    }
+}
@@ -0,0 +1,239 @@
+//! Automerge backed documents, an alternative to [`crate::ot`].
+//!
+//! Every peer (the server and each client) keeps a copy of the document as an Automerge text
+//! object. Local changes are turned into Automerge operations and exchanged as encoded Automerge
+//! changes, which merge without needing to be rebased by the server.
+
+use anyhow::{Context, bail};
+use async_lsp::lsp_types::TextDocumentContentChangeEvent;
+use automerge::{
+    ActorId, AutoCommit, ChangeHash, LoadOptions, ObjId, ObjType, PatchAction, ROOT, ReadDoc,
+    TextEncoding, Value,
+    transaction::{CommitOptions, Transactable},
+};
+use operational_transform::OperationSeq;
+
+use crate::ot;
+
+const CONTENT_KEY: &str = "content";
+/// Actor of the change creating the text object, shared by all peers
+const GENESIS_ACTOR: &[u8] = b"codlab";
+
+pub struct Document {
+    doc: AutoCommit,
+    content: ObjId,
+}
+
+impl Default for Document {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Document {
+    /// Creates an empty document.
+    ///
+    /// The change creating the text object is the same on every peer (same actor, same time),
+    /// so that they all end up editing the same object instead of conflicting ones.
+    pub fn new() -> Self {
+        let mut doc = AutoCommit::new_with_encoding(TextEncoding::UnicodeCodePoint)
+            .with_actor(ActorId::from(GENESIS_ACTOR));
+        let content = doc
+            .put_object(ROOT, CONTENT_KEY, ObjType::Text)
+            .expect("To be able to create a text object in an empty document");
+        doc.commit_with(CommitOptions::default().with_time(0));
+        doc.set_actor(ActorId::random());
+        Self { doc, content }
+    }
+
+    /// Loads a document encoded with [`Self::save`]
+    pub fn load(data: &[u8]) -> anyhow::Result<Self> {
+        let mut doc = AutoCommit::load_with_options(
+            data,
+            LoadOptions::new().text_encoding(TextEncoding::UnicodeCodePoint),
+        )
+        .context("Failed to load document")?;
+        let Some((Value::Object(ObjType::Text), content)) = doc.get(ROOT, CONTENT_KEY)? else {
+            bail!("the document has no text content");
+        };
+        doc.set_actor(ActorId::random());
+        Ok(Self { doc, content })
+    }
+
+    pub fn save(&mut self) -> Vec<u8> {
+        self.doc.save()
+    }
+
+    pub fn text(&self) -> String {
+        self.doc
+            .text(&self.content)
+            .expect("The content object to be a text object")
+    }
+
+    /// Hashes of the last changes applied, identifying the current state of the document
+    pub fn heads(&mut self) -> Vec<Vec<u8>> {
+        self.doc
+            .get_heads()
+            .into_iter()
+            .map(|head| head.as_ref().to_vec())
+            .collect()
+    }
+
+    /// Returns the content of the document in the state identified by `heads`,
+    /// or `None` if some of these changes were not received yet.
+    pub fn text_at(&mut self, heads: &[Vec<u8>]) -> Option<String> {
+        let heads = heads
+            .iter()
+            .map(|head| ChangeHash::try_from(head.as_slice()).ok())
+            .collect::<Option<Vec<_>>>()?;
+        if heads
+            .iter()
+            .any(|head| self.doc.get_change_by_hash(head).is_none())
+        {
+            return None;
+        }
+        self.doc.text_at(&self.content, &heads).ok()
+    }
+
+    /// Merges the changes of `other` which this document does not have yet
+    pub fn merge(&mut self, other: &mut Document) -> anyhow::Result<()> {
+        self.doc
+            .merge(&mut other.doc)
+            .context("Failed to merge documents")?;
+        Ok(())
+    }
+
+    /// Applies changes made in the editor.
+    /// Returns the encoded Automerge changes to send to the other peers.
+    pub fn local_change(
+        &mut self,
+        changes: &[TextDocumentContentChangeEvent],
+    ) -> anyhow::Result<Vec<u8>> {
+        let heads = self.doc.get_heads();
+        for change in changes {
+            let text = self.text();
+            let (start, end) = match change.range {
+                Some(range) => {
+                    let start = ot::position_to_offset(&text, range.start);
+                    let end = ot::position_to_offset(&text, range.end);
+                    (start.min(end), start.max(end))
+                }
+                None => (0, text.chars().count()),
+            };
+            self.doc
+                .splice_text(&self.content, start, (end - start) as isize, &change.text)
+                .context("Failed to splice text")?;
+        }
+        self.doc.commit();
+        Ok(self.doc.save_after(&heads))
+    }
+
+    /// Applies encoded Automerge changes coming from another peer.
+    /// Returns the corresponding changes to apply in the editor.
+    pub fn remote_change(
+        &mut self,
+        changes: &[u8],
+    ) -> anyhow::Result<Vec<TextDocumentContentChangeEvent>> {
+        let text = self.text();
+        let before = self.doc.get_heads();
+        self.doc
+            .load_incremental(changes)
+            .context("Failed to load remote changes")?;
+        let after = self.doc.get_heads();
+
+        let mut len = text.chars().count() as u64;
+        let mut op = OperationSeq::default();
+        op.retain(len);
+        for patch in self.doc.diff(&before, &after) {
+            if patch.obj != self.content {
+                continue;
+            }
+            let mut next = OperationSeq::default();
+            match patch.action {
+                PatchAction::SpliceText { index, value, .. } => {
+                    let value = value.make_string();
+                    next.retain(index as u64);
+                    next.insert(&value);
+                    next.retain(len - index as u64);
+                }
+                PatchAction::DeleteSeq { index, length } => {
+                    next.retain(index as u64);
+                    next.delete(length as u64);
+                    next.retain(len - (index + length) as u64);
+                }
+                _ => continue,
+            }
+            len = next.target_len() as u64;
+            op = op.compose(&next)?;
+        }
+        Ok(ot::operation_to_changes(&text, &op))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_lsp::lsp_types::{Position, Range, TextDocumentContentChangeEvent};
+    use pretty_assertions::assert_eq;
+
+    use super::Document;
+
+    fn insert(line: u32, character: u32, text: &str) -> Vec<TextDocumentContentChangeEvent> {
+        let position = Position::new(line, character);
+        vec![TextDocumentContentChangeEvent {
+            range: Some(Range::new(position, position)),
+            range_length: None,
+            text: text.to_owned(),
+        }]
+    }
+
+    #[test]
+    fn test_concurrent_changes_merge() -> anyhow::Result<()> {
+        let mut server = Document::new();
+        let mut client1 = Document::new();
+        let mut client2 = Document::new();
+
+        let changes = client1.local_change(&insert(0, 0, "hello world"))?;
+        server.remote_change(&changes)?;
+        client2.remote_change(&changes)?;
+
+        // both clients type at the same time
+        let changes1 = client1.local_change(&insert(0, 5, ","))?;
+        let changes2 = client2.local_change(&insert(0, 11, "!"))?;
+
+        server.remote_change(&changes1)?;
+        server.remote_change(&changes2)?;
+        assert_eq!(client1.remote_change(&changes2)?, insert(0, 12, "!"));
+        assert_eq!(client2.remote_change(&changes1)?, insert(0, 5, ","));
+
+        assert_eq!(server.text(), "hello, world!");
+        assert_eq!(client1.text(), server.text());
+        assert_eq!(client2.text(), server.text());
+        Ok(())
+    }
+
+    #[test]
+    fn test_loaded_document_keeps_merging() -> anyhow::Result<()> {
+        let mut server = Document::new();
+        let mut client1 = Document::new();
+        server.remote_change(&client1.local_change(&insert(0, 0, "abc"))?)?;
+
+        let mut client2 = Document::load(&server.save())?;
+        assert_eq!(client2.text(), "abc");
+        let changes = client2.local_change(&insert(0, 3, "d"))?;
+        assert_eq!(client1.remote_change(&changes)?, insert(0, 3, "d"));
+        assert_eq!(client1.text(), "abcd");
+
+        // the server did not receive the change yet
+        let heads = client2.heads();
+        assert_eq!(server.text_at(&heads), None);
+        server.remote_change(&changes)?;
+        assert_eq!(server.text_at(&heads).as_deref(), Some("abcd"));
+
+        // restored from the server, while a change is in flight
+        client1.local_change(&insert(0, 0, ">"))?;
+        let mut restored = Document::load(&server.save())?;
+        restored.merge(&mut client1)?;
+        assert_eq!(restored.text(), ">abcd");
+        Ok(())
+    }
+}
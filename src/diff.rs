@@ -0,0 +1,165 @@
+//! Minimal edits between two versions of a document.
+//!
+//! Editors may send the whole content of a document instead of the ranges which changed, and
+//! snapshots from the server replace the whole document. Sending those to peers or to the
+//! editor as they are would move every cursor and break undo, so they are turned into the
+//! smallest edits giving the same content, found with Myers' diff algorithm on chars.
+
+use std::time::{Duration, Instant};
+
+use async_lsp::lsp_types::{Range, TextDocumentContentChangeEvent, TextEdit};
+use similar::{Algorithm, DiffTag, capture_diff_slices_deadline};
+
+use crate::buffer::Buffer;
+
+/// Time after which the diff stops looking for the smallest edits, and settles for correct ones
+const DEADLINE: Duration = Duration::from_millis(100);
+
+/// Smallest edits turning `old` into `new`.
+///
+/// The edits are sorted from the end of the document to its start, so they give the same result
+/// whether they are applied one after the other (`didChange`) or all at once (`WorkspaceEdit`).
+pub fn text_edits(old: &str, new: &str) -> Vec<TextEdit> {
+    let old_chars: Vec<_> = old.chars().collect();
+    let new_chars: Vec<_> = new.chars().collect();
+    let ops = capture_diff_slices_deadline(
+        Algorithm::Myers,
+        &old_chars,
+        &new_chars,
+        Some(Instant::now() + DEADLINE),
+    );
+
+    // (old chars, new chars) replaced by each edit, merging the adjacent ones
+    let mut replaced: Vec<(std::ops::Range<usize>, std::ops::Range<usize>)> = Vec::new();
+    // end of the previous op in `old`, the index of inserts is not always right after it
+    let mut cursor = 0;
+    for op in ops {
+        let (tag, old_range, new_range) = op.as_tag_tuple();
+        let old_range = match tag {
+            DiffTag::Insert => cursor..cursor,
+            _ => old_range,
+        };
+        cursor = old_range.end;
+        if tag == DiffTag::Equal {
+            continue;
+        }
+        match replaced.last_mut() {
+            Some((old, new)) if old.end == old_range.start => {
+                old.end = old_range.end;
+                new.end = new_range.end;
+            }
+            _ => replaced.push((old_range, new_range)),
+        }
+    }
+
+    let buffer = Buffer::new(old);
+    replaced
+        .into_iter()
+        .rev()
+        .map(|(old_range, new_range)| TextEdit {
+            range: Range::new(
+                buffer.char_to_position(old_range.start),
+                buffer.char_to_position(old_range.end),
+            ),
+            new_text: new_chars[new_range].iter().collect(),
+        })
+        .collect()
+}
+
+/// Same as [`text_edits`], as the content changes of a `didChange` notification
+pub fn changes(old: &str, new: &str) -> Vec<TextDocumentContentChangeEvent> {
+    text_edits(old, new)
+        .into_iter()
+        .map(|edit| TextDocumentContentChangeEvent {
+            range: Some(edit.range),
+            range_length: None,
+            text: edit.new_text,
+        })
+        .collect()
+}
+
+/// Replaces the full document changes of a `didChange` notification, `text` being the content
+/// of the document before it, with ranged changes
+pub fn ranged_changes(
+    text: &str,
+    changes: Vec<TextDocumentContentChangeEvent>,
+) -> Vec<TextDocumentContentChangeEvent> {
+    if changes.iter().all(|change| change.range.is_some()) {
+        return changes;
+    }
+    let mut buffer = Buffer::new(text);
+    let mut ranged = Vec::new();
+    for change in changes {
+        let changes = match change.range {
+            Some(_) => vec![change],
+            None => self::changes(&buffer.to_string(), &change.text),
+        };
+        buffer.apply_changes(&changes);
+        ranged.extend(changes);
+    }
+    ranged
+}
+
+#[cfg(test)]
+mod tests {
+    use async_lsp::lsp_types::{Position, Range, TextDocumentContentChangeEvent, TextEdit};
+    use pretty_assertions::assert_eq;
+
+    use super::{ranged_changes, text_edits};
+    use crate::buffer::Buffer;
+
+    fn edit(start: (u32, u32), end: (u32, u32), text: &str) -> TextEdit {
+        TextEdit {
+            range: Range::new(Position::new(start.0, start.1), Position::new(end.0, end.1)),
+            new_text: text.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_text_edits() {
+        assert_eq!(text_edits("hello", "hello"), vec![]);
+        assert_eq!(
+            text_edits("hello\nworld", "hello\nbig world"),
+            vec![edit((1, 0), (1, 0), "big ")]
+        );
+        assert_eq!(
+            text_edits("hello\nworld", "hello"),
+            vec![edit((0, 5), (1, 5), "")]
+        );
+        assert_eq!(
+            text_edits("fn main() {}\n", "fn main() { todo!() }\n"),
+            vec![edit((0, 11), (0, 11), " todo!() ")]
+        );
+        // sorted from the end
+        assert_eq!(
+            text_edits("a\nb\nc", "A\nb\nC"),
+            vec![edit((2, 0), (2, 1), "C"), edit((0, 0), (0, 1), "A")]
+        );
+        assert_eq!(
+            text_edits("héllo wörld", "hello world"),
+            vec![edit((0, 7), (0, 8), "o"), edit((0, 1), (0, 2), "e")]
+        );
+    }
+
+    #[test]
+    fn test_ranged_changes() {
+        let text = "hello\nworld";
+        let changes = vec![
+            TextDocumentContentChangeEvent {
+                range: Some(Range::new(Position::new(0, 0), Position::new(0, 0))),
+                range_length: None,
+                text: "oh ".to_owned(),
+            },
+            TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: "oh hello\nthere".to_owned(),
+            },
+        ];
+        let ranged = ranged_changes(text, changes);
+        assert!(ranged.iter().all(|change| change.range.is_some()));
+        let mut buffer = Buffer::new(text);
+        buffer.apply_changes(&ranged);
+        assert_eq!(buffer.to_string(), "oh hello\nthere");
+    }
+}
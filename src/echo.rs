@@ -0,0 +1,226 @@
+//! Suppression of the `didChange` notifications echoing the edits codlab applied itself.
+//!
+//! Editors notify the language server of every change made to a document, including the ones
+//! applied through `workspace/applyEdit`. Sending those back to the server would apply remote
+//! edits twice and bounce them between clients forever, so each remote edit is recorded as an
+//! [`Echo`] and the `didChange` notifications matching it are dropped.
+
+use std::collections::{HashMap, VecDeque};
+
+use async_lsp::lsp_types::{DidChangeTextDocumentParams, TextDocumentContentChangeEvent, Url};
+
+/// A remote edit applied in the editor, which will come back as a `didChange` notification
+#[derive(Debug, Clone)]
+pub struct Echo {
+    pub change: DidChangeTextDocumentParams,
+    /// Content of the document once the change is applied
+    pub text: String,
+    /// Name of the user who made the change, if known
+    pub author: Option<String>,
+}
+
+/// Echoes expected from the editor, per document
+#[derive(Debug, Default)]
+pub struct EchoFilter {
+    by_uri: HashMap<Url, VecDeque<Echo>>,
+}
+
+impl EchoFilter {
+    pub fn expect(&mut self, echo: Echo) {
+        self.by_uri
+            .entry(echo.change.text_document.uri.clone())
+            .or_default()
+            .push_back(echo);
+    }
+
+    /// Stops expecting the echoes of a document, once it is closed in the editor
+    pub fn forget(&mut self, uri: &Url) {
+        self.by_uri.remove(uri);
+    }
+
+    /// Number of echoes which did not come back yet
+    pub fn pending(&self, uri: &Url) -> usize {
+        self.by_uri.get(uri).map_or(0, VecDeque::len)
+    }
+
+    /// Drops the changes of a `didChange` notification which echo remote edits.
+    /// Returns the remaining changes, which were made in the editor.
+    ///
+    /// Editors may report echoes out of order, coalesce several of them in one notification, or
+    /// send the whole content of the document (full sync). Echoes are matched by range and text,
+    /// or by the content of the document once they are applied.
+    pub fn filter(
+        &mut self,
+        uri: &Url,
+        changes: Vec<TextDocumentContentChangeEvent>,
+    ) -> Vec<TextDocumentContentChangeEvent> {
+        let Some(echoes) = self.by_uri.get_mut(uri) else {
+            return changes;
+        };
+
+        let rest = match changes.as_slice() {
+            [
+                TextDocumentContentChangeEvent {
+                    range: None, text, ..
+                },
+            ] => match echoes.iter().rposition(|echo| echo.text == *text) {
+                // the edits before the matching one were coalesced in the same notification
+                Some(index) => {
+                    echoes.drain(..=index);
+                    vec![]
+                }
+                None => changes,
+            },
+            _ => {
+                // changes apply one after the other, so only a prefix can be made of echoes
+                let mut echoed = 0;
+                while let Some(index) = echoes.iter().position(|echo| {
+                    let echoed_changes = &echo.change.content_changes;
+                    !echoed_changes.is_empty() && starts_with(&changes[echoed..], echoed_changes)
+                }) {
+                    let echo = echoes.remove(index).expect("index to be in bounds");
+                    echoed += echo.change.content_changes.len();
+                }
+                changes.into_iter().skip(echoed).collect()
+            }
+        };
+
+        if echoes.is_empty() {
+            self.by_uri.remove(uri);
+        }
+        rest
+    }
+}
+
+fn starts_with(
+    changes: &[TextDocumentContentChangeEvent],
+    prefix: &[TextDocumentContentChangeEvent],
+) -> bool {
+    changes.len() >= prefix.len()
+        && changes
+            .iter()
+            .zip(prefix)
+            .all(|(a, b)| content_changes_eq(a, b))
+}
+
+/// Compares changes ignoring the deprecated `range_length`, which editors may or may not send
+fn content_changes_eq(
+    a: &TextDocumentContentChangeEvent,
+    b: &TextDocumentContentChangeEvent,
+) -> bool {
+    a.range == b.range && a.text == b.text
+}
+
+#[cfg(test)]
+mod tests {
+    use async_lsp::lsp_types::{
+        DidChangeTextDocumentParams, Position, Range, TextDocumentContentChangeEvent, Url,
+        VersionedTextDocumentIdentifier,
+    };
+    use pretty_assertions::assert_eq;
+
+    use super::{Echo, EchoFilter};
+
+    fn uri() -> Url {
+        Url::parse("file:///tmp/src/lib.rs").unwrap()
+    }
+
+    fn insert(line: u32, character: u32, text: &str) -> TextDocumentContentChangeEvent {
+        let position = Position::new(line, character);
+        TextDocumentContentChangeEvent {
+            range: Some(Range::new(position, position)),
+            range_length: None,
+            text: text.to_owned(),
+        }
+    }
+
+    fn full(text: &str) -> TextDocumentContentChangeEvent {
+        TextDocumentContentChangeEvent {
+            range: None,
+            range_length: None,
+            text: text.to_owned(),
+        }
+    }
+
+    fn echo(changes: Vec<TextDocumentContentChangeEvent>, text: &str) -> Echo {
+        Echo {
+            change: DidChangeTextDocumentParams {
+                text_document: VersionedTextDocumentIdentifier::new(uri(), 0),
+                content_changes: changes,
+            },
+            text: text.to_owned(),
+            author: None,
+        }
+    }
+
+    #[test]
+    fn test_local_changes_pass_through() {
+        let mut filter = EchoFilter::default();
+        assert_eq!(
+            filter.filter(&uri(), vec![insert(0, 0, "a")]),
+            vec![insert(0, 0, "a")]
+        );
+
+        filter.expect(echo(vec![insert(0, 0, "a")], "a"));
+        assert_eq!(
+            filter.filter(&uri(), vec![insert(0, 0, "b")]),
+            vec![insert(0, 0, "b")]
+        );
+        assert_eq!(filter.filter(&uri(), vec![full("b")]), vec![full("b")]);
+        assert_eq!(filter.pending(&uri()), 1);
+    }
+
+    #[test]
+    fn test_out_of_order_echoes() {
+        let mut filter = EchoFilter::default();
+        filter.expect(echo(vec![insert(0, 0, "a")], "a"));
+        filter.expect(echo(vec![insert(0, 1, "b")], "ab"));
+
+        assert_eq!(filter.filter(&uri(), vec![insert(0, 1, "b")]), vec![]);
+        assert_eq!(filter.pending(&uri()), 1);
+        assert_eq!(filter.filter(&uri(), vec![insert(0, 0, "a")]), vec![]);
+        assert_eq!(filter.pending(&uri()), 0);
+    }
+
+    #[test]
+    fn test_coalesced_echoes() {
+        let mut filter = EchoFilter::default();
+        filter.expect(echo(vec![insert(0, 0, "a")], "a"));
+        filter.expect(echo(vec![insert(0, 1, "b"), insert(0, 0, "c")], "cab"));
+        filter.expect(echo(vec![insert(0, 3, "d")], "cabd"));
+
+        // in reverse order, followed by a local change
+        let mut range_length = insert(0, 0, "a");
+        range_length.range_length = Some(0);
+        assert_eq!(
+            filter.filter(
+                &uri(),
+                vec![
+                    insert(0, 1, "b"),
+                    insert(0, 0, "c"),
+                    range_length,
+                    insert(0, 4, "!"),
+                ]
+            ),
+            vec![insert(0, 4, "!")]
+        );
+        assert_eq!(filter.pending(&uri()), 1);
+    }
+
+    #[test]
+    fn test_coalesced_full_sync_echoes() {
+        let mut filter = EchoFilter::default();
+        filter.expect(echo(vec![insert(0, 0, "a")], "a"));
+        filter.expect(echo(vec![insert(0, 1, "b")], "ab"));
+        filter.expect(echo(vec![insert(0, 2, "c")], "abc"));
+
+        assert_eq!(filter.filter(&uri(), vec![full("ab")]), vec![]);
+        assert_eq!(filter.pending(&uri()), 1);
+        assert_eq!(
+            filter.filter(&uri(), vec![full("abc!")]),
+            vec![full("abc!")]
+        );
+        assert_eq!(filter.filter(&uri(), vec![full("abc")]), vec![]);
+        assert_eq!(filter.pending(&uri()), 0);
+    }
+}
@@ -0,0 +1,172 @@
+//! Encoding of the characters of LSP positions.
+//!
+//! Positions count the characters of a line in UTF-16 code units unless the editor and the
+//! language server negotiate another encoding in `initialize` (LSP 3.17). Between the clients
+//! and the server, positions are always counted in chars (UTF-32), as in [`Buffer`] and the sync
+//! engines: each client converts the positions exchanged with its editor.
+
+use async_lsp::lsp_types::{PositionEncodingKind, Range, TextDocumentContentChangeEvent};
+
+use crate::buffer::Buffer;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PositionEncoding {
+    Utf8,
+    /// Must be supported by every editor
+    #[default]
+    Utf16,
+    Utf32,
+}
+
+impl PositionEncoding {
+    /// Encoding of the positions exchanged between the clients and the server
+    pub const WIRE: Self = Self::Utf32;
+
+    /// Picks the encoding to use with an editor supporting `supported`, avoiding conversions
+    /// when possible
+    pub fn negotiate(supported: Option<&[PositionEncodingKind]>) -> Self {
+        let supported = supported.unwrap_or_default();
+        [Self::Utf32, Self::Utf8]
+            .into_iter()
+            .find(|encoding| supported.contains(&encoding.kind()))
+            .unwrap_or_default()
+    }
+
+    pub fn kind(self) -> PositionEncodingKind {
+        match self {
+            Self::Utf8 => PositionEncodingKind::UTF8,
+            Self::Utf16 => PositionEncodingKind::UTF16,
+            Self::Utf32 => PositionEncodingKind::UTF32,
+        }
+    }
+
+    /// Number of code units encoding `char`
+    fn len(self, char: char) -> u32 {
+        match self {
+            Self::Utf8 => char.len_utf8() as u32,
+            Self::Utf16 => char.len_utf16() as u32,
+            Self::Utf32 => 1,
+        }
+    }
+
+    /// Converts the character of a position in `line` to the encoding `to`.
+    /// A position in the middle of a char is moved after it, and the code units past the end of
+    /// the line are kept as they are.
+    pub fn convert(self, to: Self, line: impl IntoIterator<Item = char>, character: u32) -> u32 {
+        if self == to {
+            return character;
+        }
+        let mut from_units = 0;
+        let mut to_units = 0;
+        for char in line {
+            if from_units >= character {
+                return to_units;
+            }
+            from_units += self.len(char);
+            to_units += to.len(char);
+        }
+        to_units + character.saturating_sub(from_units)
+    }
+}
+
+/// Converts the ranges of content changes from the encoding `from` to the encoding `to`, the
+/// changes applying one after the other to `buffer`, which ends up with the changes applied
+pub fn convert_changes(
+    buffer: &mut Buffer,
+    changes: &[TextDocumentContentChangeEvent],
+    from: PositionEncoding,
+    to: PositionEncoding,
+) -> Vec<TextDocumentContentChangeEvent> {
+    let convert = |buffer: &Buffer, range: Range, to| {
+        Range::new(
+            buffer.convert_position(range.start, from, to),
+            buffer.convert_position(range.end, from, to),
+        )
+    };
+    changes
+        .iter()
+        .map(|change| {
+            let Some(range) = change.range else {
+                buffer.apply_change(change);
+                return change.clone();
+            };
+            let converted = TextDocumentContentChangeEvent {
+                range: Some(convert(buffer, range, to)),
+                // counted in the encoding of the editor, and deprecated anyway
+                range_length: None,
+                text: change.text.clone(),
+            };
+            buffer.apply_change(&TextDocumentContentChangeEvent {
+                range: Some(convert(buffer, range, PositionEncoding::WIRE)),
+                ..converted.clone()
+            });
+            converted
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use async_lsp::lsp_types::{
+        Position, PositionEncodingKind, Range, TextDocumentContentChangeEvent,
+    };
+    use pretty_assertions::assert_eq;
+
+    use super::{PositionEncoding, convert_changes};
+    use crate::buffer::Buffer;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(PositionEncoding::negotiate(None), PositionEncoding::Utf16);
+        assert_eq!(
+            PositionEncoding::negotiate(Some(&[
+                PositionEncodingKind::UTF16,
+                PositionEncodingKind::UTF8
+            ])),
+            PositionEncoding::Utf8
+        );
+        assert_eq!(
+            PositionEncoding::negotiate(Some(&[
+                PositionEncodingKind::UTF8,
+                PositionEncodingKind::UTF32
+            ])),
+            PositionEncoding::Utf32
+        );
+    }
+
+    #[test]
+    fn test_convert() {
+        use PositionEncoding::*;
+        let line = "a😀é!";
+        assert_eq!(Utf16.convert(Utf32, line.chars(), 3), 2);
+        assert_eq!(Utf32.convert(Utf16, line.chars(), 2), 3);
+        assert_eq!(Utf32.convert(Utf8, line.chars(), 4), 8);
+        assert_eq!(Utf8.convert(Utf16, line.chars(), 7), 4);
+        // in the middle of the emoji
+        assert_eq!(Utf16.convert(Utf32, line.chars(), 2), 2);
+        // past the end of the line
+        assert_eq!(Utf16.convert(Utf32, line.chars(), 7), 6);
+    }
+
+    #[test]
+    fn test_convert_changes() {
+        let change = |line, character, text: &str| TextDocumentContentChangeEvent {
+            range: Some(Range::new(
+                Position::new(line, character),
+                Position::new(line, character),
+            )),
+            range_length: None,
+            text: text.to_owned(),
+        };
+        let mut buffer = Buffer::new("😀\n");
+        // the second change is after the emoji inserted by the first one
+        let converted = convert_changes(
+            &mut buffer,
+            &[change(0, 2, "😀"), change(0, 4, "!")],
+            PositionEncoding::Utf16,
+            PositionEncoding::Utf32,
+        );
+        assert_eq!(converted, vec![change(0, 1, "😀"), change(0, 2, "!")]);
+        assert_eq!(buffer.to_string(), "😀😀!\n");
+    }
+}
@@ -0,0 +1,212 @@
+//! Edit history of the shared documents.
+//!
+//! The server records every change it applies with its author, so that clients can find out
+//! who wrote a part of a document and when. The history of a range is found by walking the
+//! edits from the newest one, moving the range back through each of them.
+
+use anyhow::Context;
+use async_lsp::lsp_types::{Range, TextDocumentContentChangeEvent, notification::Notification};
+use operational_transform::{Operation, OperationSeq};
+
+use crate::{
+    buffer::Buffer,
+    common::timestamp,
+    messages::{Edit, EditHistory, Revision},
+    ot,
+};
+
+/// Sent to the editor with the history it asked for
+pub enum HistoryNotification {}
+
+impl Notification for HistoryNotification {
+    type Params = EditHistory;
+    const METHOD: &'static str = "codlab/history";
+}
+
+#[derive(Debug)]
+struct Entry {
+    edit: Edit,
+    op: OperationSeq,
+    /// Reverts `op`, used to move ranges to older revisions
+    inverse: OperationSeq,
+}
+
+/// Edits applied to a document, oldest first
+#[derive(Debug, Default)]
+pub struct History {
+    /// Revision of the document before the oldest edit, the edits of a restored document are
+    /// not kept, see [`crate::storage`]
+    base: Revision,
+    entries: Vec<Entry>,
+}
+
+impl History {
+    /// History of a document restored at `revision`, without its older edits
+    pub fn starting_at(revision: Revision) -> Self {
+        Self {
+            base: revision,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn revision(&self) -> Revision {
+        self.base + self.entries.len() as Revision
+    }
+
+    pub fn last(&self) -> Option<&Edit> {
+        self.entries.last().map(|entry| &entry.edit)
+    }
+
+    /// Records changes applied by `author` to the document, `before` being its content before
+    /// them
+    pub fn record(
+        &mut self,
+        before: &str,
+        author: Option<String>,
+        changes: Vec<TextDocumentContentChangeEvent>,
+    ) -> anyhow::Result<()> {
+        let op = ot::changes_to_operation(before, &changes)
+            .context("Failed to record change in the history")?;
+        let inverse = op.invert(before);
+        self.entries.push(Entry {
+            edit: Edit {
+                revision: self.revision() + 1,
+                author,
+                timestamp: timestamp(),
+                changes,
+            },
+            op,
+            inverse,
+        });
+        Ok(())
+    }
+
+    /// Edits which touched `range` of `buffer`, the current content of the document, or every
+    /// edit without a range. An empty range stands for the char after it.
+    pub fn edits(&self, buffer: &Buffer, range: Option<Range>) -> Vec<Edit> {
+        let Some(range) = range else {
+            return self
+                .entries
+                .iter()
+                .map(|entry| entry.edit.clone())
+                .collect();
+        };
+        let mut start = buffer.position_to_char(range.start);
+        let mut end = buffer.position_to_char(range.end).max(start + 1);
+        let mut edits = Vec::new();
+        for entry in self.entries.iter().rev() {
+            if touches(&entry.op, start, end) {
+                edits.push(entry.edit.clone());
+            }
+            start = ot::transform_offset(&entry.inverse, start);
+            end = ot::transform_offset(&entry.inverse, end);
+            if start >= end {
+                // the whole range was inserted by this edit and the later ones
+                break;
+            }
+        }
+        edits.reverse();
+        edits
+    }
+}
+
+/// Whether `op` inserted text in the offsets `start..end` of its result, or deleted text between
+/// two of them
+fn touches(op: &OperationSeq, start: usize, end: usize) -> bool {
+    // offset in the text after `op`
+    let mut index = 0;
+    for operation in op.ops() {
+        if index >= end {
+            break;
+        }
+        match operation {
+            Operation::Retain(n) => index += *n as usize,
+            Operation::Delete(_) => {
+                if start < index {
+                    return true;
+                }
+            }
+            Operation::Insert(s) => {
+                let inserted = index + s.chars().count();
+                if start < inserted {
+                    return true;
+                }
+                index = inserted;
+            }
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use async_lsp::lsp_types::{Position, Range, TextDocumentContentChangeEvent};
+    use pretty_assertions::assert_eq;
+
+    use super::History;
+    use crate::buffer::Buffer;
+
+    fn replace(range: Range, text: &str) -> Vec<TextDocumentContentChangeEvent> {
+        vec![TextDocumentContentChangeEvent {
+            range: Some(range),
+            range_length: None,
+            text: text.to_owned(),
+        }]
+    }
+
+    fn range(start: (u32, u32), end: (u32, u32)) -> Range {
+        Range::new(Position::new(start.0, start.1), Position::new(end.0, end.1))
+    }
+
+    #[test]
+    fn test_history_of_range() -> anyhow::Result<()> {
+        let mut history = History::default();
+        let mut buffer = Buffer::default();
+        let mut apply = |author: &str, changes: Vec<TextDocumentContentChangeEvent>| {
+            let before = buffer.to_string();
+            buffer.apply_changes(&changes);
+            history.record(&before, Some(author.to_owned()), changes)
+        };
+        apply("alice", replace(range((0, 0), (0, 0)), "hello\nworld"))?;
+        apply("bob", replace(range((1, 0), (1, 5)), "there"))?;
+        apply("carol", replace(range((0, 0), (0, 0)), "oh "))?;
+
+        let authors = |range| {
+            history
+                .edits(&buffer, range)
+                .into_iter()
+                .map(|edit| (edit.revision, edit.author.unwrap()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            authors(None),
+            vec![(1, "alice".into()), (2, "bob".into()), (3, "carol".into())]
+        );
+        // "hello"
+        assert_eq!(
+            authors(Some(range((0, 3), (0, 8)))),
+            vec![(1, "alice".into())]
+        );
+        // "her"
+        assert_eq!(
+            authors(Some(range((1, 1), (1, 4)))),
+            vec![(2, "bob".into())]
+        );
+        // "oh"
+        assert_eq!(
+            authors(Some(range((0, 0), (0, 2)))),
+            vec![(3, "carol".into())]
+        );
+        // "h o", across the insertion of "oh "
+        assert_eq!(
+            authors(Some(range((0, 1), (0, 4)))),
+            vec![(1, "alice".into()), (3, "carol".into())]
+        );
+        // the cursor before "there", which replaced the deleted "world"
+        assert_eq!(
+            authors(Some(range((1, 0), (1, 0)))),
+            vec![(2, "bob".into())]
+        );
+        Ok(())
+    }
+}
@@ -1,43 +1,51 @@
+pub mod buffer;
 pub mod change;
 pub mod common;
+pub mod config;
+pub mod crdt;
+pub mod diff;
+pub mod echo;
+pub mod encoding;
+pub mod history;
 pub mod messages;
-pub mod peekable_channel;
+pub mod ot;
+pub mod presence;
+pub mod recording;
+pub mod storage;
+pub mod version;
+pub mod workspace;
 
 use std::collections::HashMap;
 
+use anyhow::Context as _;
 use async_lsp::lsp_types::{
-    ApplyWorkspaceEditParams, DidChangeTextDocumentParams, Position, Range, TextEdit, WorkspaceEdit,
+    ApplyWorkspaceEditParams, DidChangeTextDocumentParams, TextEdit, WorkspaceEdit,
 };
-use tracing::warn;
 
 // TODO: move this somewhere else
+/// Converts ranged changes to an edit to apply in the editor, full document changes must be
+/// turned into ranged ones first, see [`diff::ranged_changes`]
 pub fn change_event_to_workspace_edit(
     event: &DidChangeTextDocumentParams,
-) -> ApplyWorkspaceEditParams {
-    ApplyWorkspaceEditParams {
-        // TODO: username in edit label
-        label: Some("remote editor".to_owned()),
+    author: Option<&str>,
+) -> anyhow::Result<ApplyWorkspaceEditParams> {
+    let edits = event
+        .content_changes
+        .iter()
+        .map(|change| {
+            Ok(TextEdit {
+                range: change
+                    .range
+                    .context("a full document change can't be applied")?,
+                new_text: change.text.clone(),
+            })
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok(ApplyWorkspaceEditParams {
+        label: Some(author.unwrap_or("remote editor").to_owned()),
         edit: WorkspaceEdit {
-            changes: Some(HashMap::from([(
-                event.text_document.uri.clone(),
-                event
-                    .content_changes
-                    .iter()
-                    .map(|change| {
-                        // if there is no range, just replace the whole document
-                        let range = change.range.unwrap_or(Range::new(
-                            Position::new(0, 0),
-                            // some editors might not like this
-                            Position::new(u32::MAX, u32::MAX),
-                        ));
-                        TextEdit {
-                            range,
-                            new_text: change.text.clone(),
-                        }
-                    })
-                    .collect(),
-            )])),
+            changes: Some(HashMap::from([(event.text_document.uri.clone(), edits)])),
             ..Default::default()
         },
-    }
+    })
 }
@@ -1,26 +1,322 @@
-use async_lsp::lsp_types::DidChangeTextDocumentParams;
+use async_lsp::lsp_types::{
+    DidChangeTextDocumentParams, Position, Range, TextDocumentContentChangeEvent, Url,
+    notification::Notification,
+};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+/// Number of changes the server applied to a document
+pub type Revision = u64;
+
+/// How the server keeps documents in sync
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum SyncEngine {
+    /// Operational transformation, see [`crate::ot`]
+    #[default]
+    Ot,
+    /// Automerge CRDT, see [`crate::crdt`]
+    Crdt,
+}
+
+/// What a client may do in its session
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    Editor,
+    /// Receives the changes of the editors but can't make any
+    Observer,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Change {
     pub id: Uuid,
+    /// Name of the user who made the change, set by the server when broadcasting it
+    #[serde(default)]
+    pub author: Option<String>,
+    /// When sent by a client: the revision the change was made on top of.
+    /// When sent by the server: the revision resulting from the change.
+    pub revision: Revision,
     pub change: DidChangeTextDocumentParams,
 }
 
+/// Encoded Automerge changes, used with [`SyncEngine::Crdt`]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CrdtChange {
+    pub id: Uuid,
+    /// Name of the user who made the change, set by the server when broadcasting it
+    #[serde(default)]
+    pub author: Option<String>,
+    pub uri: Url,
+    pub changes: Vec<u8>,
+}
+
+/// Content of a document on the server
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub uri: Url,
+    /// Revision of the document, used with [`SyncEngine::Ot`]
+    pub revision: Revision,
+    pub text: String,
+    /// Encoded Automerge document, used with [`SyncEngine::Crdt`]
+    pub crdt: Option<Vec<u8>>,
+}
+
+/// Hash of the content of a document on a client, at a given state
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Checksum {
+    pub uri: Url,
+    /// Revision of the document, used with [`SyncEngine::Ot`]
+    pub revision: Revision,
+    /// Heads of the Automerge document, used with [`SyncEngine::Crdt`]
+    pub crdt_heads: Option<Vec<Vec<u8>>>,
+    pub hash: u64,
+}
+
+impl Checksum {
+    /// Hashes a document content, the same way on every platform (64 bits FNV-1a)
+    pub fn hash(text: &str) -> u64 {
+        text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+    }
+}
+
+/// Cursor and selections of a user in a document, see [`crate::presence`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Presence {
+    pub user: Uuid,
+    /// Name of the user, set by the server when broadcasting the presence
+    #[serde(default)]
+    pub username: Option<String>,
+    pub uri: Url,
+    /// Revision the positions refer to, used with [`SyncEngine::Ot`]
+    pub revision: Revision,
+    pub cursor: Position,
+    pub selections: Vec<Range>,
+}
+
+/// A change applied to a document by the server, see [`crate::history`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Edit {
+    /// Revision resulting from the change, with [`SyncEngine::Crdt`] the number of changes
+    /// applied to the document
+    pub revision: Revision,
+    pub author: Option<String>,
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    /// Changes as applied by the server, relative to the document at the previous revision
+    pub changes: Vec<TextDocumentContentChangeEvent>,
+}
+
+/// Edits made to a document, oldest first
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EditHistory {
+    pub uri: Url,
+    pub edits: Vec<Edit>,
+}
+
+/// A document saved by a peer
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SavedDocument {
+    pub uri: Url,
+    /// Name of the peer, if known
+    pub author: Option<String>,
+}
+
+/// A client of the session, see [`ClientMessage::ListPeers`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Peer {
+    /// Identifies the client in the logs of the server
+    pub id: u32,
+    pub name: Option<String>,
+    pub role: Role,
+    /// Documents open in the editor of the peer
+    pub open: Vec<Url>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PeersParams {
+    pub peers: Vec<Peer>,
+}
+
+/// Sent to the editor with the peers of the session, when it lists them
+pub enum PeersNotification {}
+
+impl Notification for PeersNotification {
+    type Params = PeersParams;
+    const METHOD: &'static str = "codlab/peers";
+}
+
+/// Sent to the editor when a peer saved a document, so that it can save its own copy
+pub enum DocumentSavedNotification {}
+
+impl Notification for DocumentSavedNotification {
+    type Params = SavedDocument;
+    const METHOD: &'static str = "codlab/documentSaved";
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum CommonMessage {
     Change(Change),
+    CrdtChange(CrdtChange),
+}
+
+impl CommonMessage {
+    pub fn id(&self) -> Uuid {
+        match self {
+            CommonMessage::Change(change) => change.id,
+            CommonMessage::CrdtChange(change) => change.id,
+        }
+    }
+
+    pub fn uri(&self) -> &Url {
+        match self {
+            CommonMessage::Change(change) => &change.change.text_document.uri,
+            CommonMessage::CrdtChange(change) => &change.uri,
+        }
+    }
+
+    pub fn uri_mut(&mut self) -> &mut Url {
+        match self {
+            CommonMessage::Change(change) => &mut change.change.text_document.uri,
+            CommonMessage::CrdtChange(change) => &mut change.uri,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ClientMessage {
-    /// Confirms that a change was applied
+    /// Sent once connected, the name of the user is attached to its changes.
+    /// The client is an observer until then, and can't change its role afterwards.
+    Hello {
+        username: Option<String>,
+        #[serde(default)]
+        role: Role,
+    },
+    /// Leaves the current session for the one with this name,
+    /// answered with [`ServerMessage::Joined`] and a snapshot of its documents
+    JoinSession(String),
+    /// Confirms that a change broadcasted by the server was applied in the editor
     AcknowledgeChange(Uuid),
+    /// Asks for a [`ServerMessage::Snapshot`] of every shared document
+    RequestSnapshot,
+    /// Sent regularly so that the server can detect desyncs,
+    /// answered with a [`ServerMessage::Correction`] for each document which differs
+    Checksums(Vec<Checksum>),
+    /// The cursor or the selections of the user moved
+    Presence(Presence),
+    /// Asks for the [`ServerMessage::History`] of a document, only the edits which touched
+    /// `range` if given, in the current content of the document
+    RequestHistory {
+        uri: Url,
+        range: Option<Range>,
+    },
+    /// The user opened a document in the editor.
+    /// `content` shares it if the client does not know it yet: the change inserting its content
+    /// in the empty document. Applied if the session does not have the document yet, in which
+    /// case the peers receive a [`ServerMessage::DocumentOpened`], otherwise answered with a
+    /// [`ServerMessage::Correction`] to replace the content of the editor with the shared one.
+    OpenDocument {
+        uri: Url,
+        content: Option<CommonMessage>,
+    },
+    /// The user closed a document in the editor
+    CloseDocument(Url),
+    /// The user saved a document, the peers receive a [`ServerMessage::DocumentSaved`]
+    SaveDocument(Url),
+    /// Asks for the [`ServerMessage::Peers`] of the session
+    ListPeers,
     Common(CommonMessage),
 }
 
+impl ClientMessage {
+    /// Documents the message is about, see [`crate::workspace`]
+    pub fn uris_mut(&mut self) -> Vec<&mut Url> {
+        match self {
+            ClientMessage::Hello { .. }
+            | ClientMessage::JoinSession(_)
+            | ClientMessage::AcknowledgeChange(_)
+            | ClientMessage::RequestSnapshot
+            | ClientMessage::ListPeers => vec![],
+            ClientMessage::Checksums(checksums) => checksums
+                .iter_mut()
+                .map(|checksum| &mut checksum.uri)
+                .collect(),
+            ClientMessage::Presence(presence) => vec![&mut presence.uri],
+            ClientMessage::RequestHistory { uri, .. }
+            | ClientMessage::CloseDocument(uri)
+            | ClientMessage::SaveDocument(uri) => vec![uri],
+            ClientMessage::OpenDocument { uri, content } => {
+                let mut uris = vec![uri];
+                uris.extend(content.as_mut().map(CommonMessage::uri_mut));
+                uris
+            }
+            ClientMessage::Common(change) => vec![change.uri_mut()],
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ServerMessage {
+    /// Sent on connection, tells the client which kind of changes to send
+    SyncEngine(SyncEngine),
+    /// The client left its session for the one with this name,
+    /// the changes received afterwards are about the documents of the new session
+    Joined(String),
+    /// Confirms that a change sent by this client was applied, resulting in `revision`
+    AcknowledgeChange {
+        uri: Url,
+        id: Uuid,
+        revision: Revision,
+    },
+    /// Current content of every shared document
+    Snapshot(Vec<Snapshot>),
+    /// Content of a document whose [`Checksum`] did not match, to restore it
+    Correction(Snapshot),
+    /// The cursor or the selections of a peer moved, the positions are rebased on the current
+    /// revision of the document
+    Presence(Presence),
+    /// A peer left the session, its cursor should not be shown anymore
+    UserLeft(Uuid),
+    /// Answers [`ClientMessage::RequestHistory`]
+    History(EditHistory),
+    /// A peer shared a document it opened, see [`ClientMessage::OpenDocument`]
+    DocumentOpened {
+        /// Name of the peer, if known
+        author: Option<String>,
+        snapshot: Snapshot,
+    },
+    /// A peer saved a document
+    DocumentSaved(SavedDocument),
+    /// Answers [`ClientMessage::ListPeers`] with the other clients of the session
+    Peers(Vec<Peer>),
     Common(CommonMessage),
 }
+
+impl ServerMessage {
+    /// Documents the message is about, see [`crate::workspace`]
+    pub fn uris_mut(&mut self) -> Vec<&mut Url> {
+        match self {
+            ServerMessage::SyncEngine(_)
+            | ServerMessage::Joined(_)
+            | ServerMessage::UserLeft(_) => vec![],
+            ServerMessage::AcknowledgeChange { uri, .. } => vec![uri],
+            ServerMessage::Snapshot(snapshots) => snapshots
+                .iter_mut()
+                .map(|snapshot| &mut snapshot.uri)
+                .collect(),
+            ServerMessage::Correction(snapshot)
+            | ServerMessage::DocumentOpened { snapshot, .. } => {
+                vec![&mut snapshot.uri]
+            }
+            ServerMessage::Presence(presence) => vec![&mut presence.uri],
+            ServerMessage::History(history) => vec![&mut history.uri],
+            ServerMessage::DocumentSaved(saved) => vec![&mut saved.uri],
+            ServerMessage::Peers(peers) => {
+                peers.iter_mut().flat_map(|peer| &mut peer.open).collect()
+            }
+            ServerMessage::Common(change) => vec![change.uri_mut()],
+        }
+    }
+}
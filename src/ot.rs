@@ -0,0 +1,533 @@
+//! Operational transformation of document changes.
+//!
+//! The server keeps a [`Document`] history per file and rebases every incoming change on
+//! top of the operations its author had not seen yet. Each client keeps a [`ClientDocument`]
+//! holding its own changes that the server has not acknowledged yet, and rebases the remote
+//! changes it receives over them. This is the classic ot.js client/server protocol: a client
+//! only ever has one change in flight, the next ones are buffered until it is acknowledged.
+
+use anyhow::{Context, bail};
+use async_lsp::lsp_types::{Position, Range, TextDocumentContentChangeEvent};
+use operational_transform::{Operation, OperationSeq};
+
+use crate::{buffer::Buffer, messages::Revision};
+
+/// Converts an LSP position to a char offset in `text`.
+/// Positions past the end of a line or of the document are clamped, as the LSP spec asks.
+pub fn position_to_offset(text: &str, position: Position) -> usize {
+    let mut offset = 0;
+    let mut lines = text.split('\n');
+    for _ in 0..position.line {
+        match lines.next() {
+            Some(line) => offset += line.chars().count() + 1,
+            None => return text.chars().count(),
+        }
+    }
+    match lines.next() {
+        Some(line) => offset + line.chars().count().min(position.character as usize),
+        None => text.chars().count(),
+    }
+}
+
+/// Converts a char offset in `text` to an LSP position.
+pub fn offset_to_position(text: &str, offset: usize) -> Position {
+    let mut position = Position::new(0, 0);
+    for char in text.chars().take(offset) {
+        match char {
+            '\n' => {
+                position.character = 0;
+                position.line += 1;
+            }
+            _ => position.character += 1,
+        }
+    }
+    position
+}
+
+/// Converts a single LSP change to an operation on `text`.
+/// A change without a range replaces the whole document.
+pub fn change_to_operation(text: &str, change: &TextDocumentContentChangeEvent) -> OperationSeq {
+    let len = text.chars().count();
+    let (start, end) = match change.range {
+        Some(range) => {
+            let start = position_to_offset(text, range.start);
+            let end = position_to_offset(text, range.end);
+            (start.min(end), start.max(end))
+        }
+        None => (0, len),
+    };
+    let mut op = OperationSeq::default();
+    op.retain(start as u64);
+    op.delete((end - start) as u64);
+    op.insert(&change.text);
+    op.retain((len - end) as u64);
+    op
+}
+
+/// Converts the content changes of a `didChange` notification, which apply one after the other,
+/// to a single operation on `text`.
+pub fn changes_to_operation(
+    text: &str,
+    changes: &[TextDocumentContentChangeEvent],
+) -> anyhow::Result<OperationSeq> {
+    let mut text = text.to_owned();
+    let mut op = OperationSeq::default();
+    op.retain(text.chars().count() as u64);
+    for change in changes {
+        let next = change_to_operation(&text, change);
+        text = next.apply(&text)?;
+        op = op.compose(&next)?;
+    }
+    Ok(op)
+}
+
+/// Converts an operation on `text` to ranged LSP changes.
+///
+/// The changes are sorted from the end of the document to its start, so they give the same
+/// result whether they are applied one after the other (`didChange`) or all at once
+/// (`WorkspaceEdit`).
+pub fn operation_to_changes(text: &str, op: &OperationSeq) -> Vec<TextDocumentContentChangeEvent> {
+    // (start, end, new text) in char offsets of `text`
+    let mut edits: Vec<(usize, usize, String)> = vec![];
+    let mut offset = 0;
+    for operation in op.ops() {
+        match operation {
+            Operation::Retain(n) => offset += *n as usize,
+            Operation::Delete(n) => {
+                match edits.last_mut() {
+                    Some((_, end, _)) if *end == offset => *end += *n as usize,
+                    _ => edits.push((offset, offset + *n as usize, String::new())),
+                }
+                offset += *n as usize;
+            }
+            Operation::Insert(s) => match edits.last_mut() {
+                Some((_, end, new_text)) if *end == offset => new_text.push_str(s),
+                _ => edits.push((offset, offset, s.clone())),
+            },
+        }
+    }
+    edits
+        .into_iter()
+        .rev()
+        .map(|(start, end, new_text)| TextDocumentContentChangeEvent {
+            range: Some(Range::new(
+                offset_to_position(text, start),
+                offset_to_position(text, end),
+            )),
+            range_length: None,
+            text: new_text,
+        })
+        .collect()
+}
+
+/// Moves a char offset in a text through an operation on it.
+/// Text inserted right at the offset ends up before it, an offset inside deleted text moves to
+/// where the deletion was.
+pub fn transform_offset(op: &OperationSeq, offset: usize) -> usize {
+    // offset in the text before `op`
+    let mut index = 0;
+    let mut transformed = offset;
+    for operation in op.ops() {
+        if index > offset {
+            break;
+        }
+        match operation {
+            Operation::Retain(n) => index += *n as usize,
+            Operation::Delete(n) => {
+                transformed -= (*n as usize).min(offset - index);
+                index += *n as usize;
+            }
+            Operation::Insert(s) => transformed += s.chars().count(),
+        }
+    }
+    transformed
+}
+
+/// Moves positions in `before` through `op`, `after` being the result of `op`
+pub fn transform_positions(
+    before: &str,
+    after: &str,
+    op: &OperationSeq,
+    positions: &[Position],
+) -> Vec<Position> {
+    positions
+        .iter()
+        .map(|position| {
+            let offset = transform_offset(op, position_to_offset(before, *position));
+            offset_to_position(after, offset)
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+struct HistoryEntry {
+    op: OperationSeq,
+    /// Reverts `op`, used to rebuild the document at older revisions
+    inverse: OperationSeq,
+}
+
+/// History of a document kept by the server, whose content is the authoritative [`Buffer`]
+#[derive(Debug, Default)]
+pub struct Document {
+    /// Revision of the first operation of `history`, the older ones are not kept
+    base: Revision,
+    history: Vec<HistoryEntry>,
+}
+
+impl Document {
+    /// Starts the history of a document at `revision`, the changes made on top of older revisions
+    /// can't be rebased anymore
+    pub fn at_revision(revision: Revision) -> Self {
+        Self {
+            base: revision,
+            history: Vec::new(),
+        }
+    }
+
+    pub fn revision(&self) -> Revision {
+        self.base + self.history.len() as Revision
+    }
+
+    /// Operations applied since `revision`
+    fn since(&self, revision: Revision) -> anyhow::Result<&[HistoryEntry]> {
+        if revision > self.revision() {
+            bail!(
+                "revision {revision} is in the future, the document is at revision {}",
+                self.revision()
+            );
+        }
+        if revision < self.base {
+            bail!(
+                "revision {revision} is too old, the history starts at revision {}",
+                self.base
+            );
+        }
+        Ok(&self.history[(revision - self.base) as usize..])
+    }
+
+    /// Returns the content of the document at `revision`, `buffer` being its current content
+    pub fn text_at(&self, buffer: &Buffer, revision: Revision) -> anyhow::Result<String> {
+        let mut text = buffer.to_string();
+        for entry in self.since(revision)?.iter().rev() {
+            text = entry.inverse.apply(&text)?;
+        }
+        Ok(text)
+    }
+
+    /// Moves positions in the document at `revision` through the operations applied since then
+    pub fn transform_positions(
+        &self,
+        buffer: &Buffer,
+        revision: Revision,
+        positions: &[Position],
+    ) -> anyhow::Result<Vec<Position>> {
+        let base = self.text_at(buffer, revision)?;
+        let since = self.since(revision)?;
+        Ok(positions
+            .iter()
+            .map(|position| {
+                let mut offset = position_to_offset(&base, *position);
+                for entry in since {
+                    offset = transform_offset(&entry.op, offset);
+                }
+                buffer.char_to_position(offset)
+            })
+            .collect())
+    }
+
+    /// Rebases `changes`, made on top of `revision`, over the operations applied since then and
+    /// applies them to `buffer`.
+    /// Returns the rebased changes, relative to the document before they were applied.
+    pub fn apply(
+        &mut self,
+        buffer: &mut Buffer,
+        revision: Revision,
+        changes: &[TextDocumentContentChangeEvent],
+    ) -> anyhow::Result<Vec<TextDocumentContentChangeEvent>> {
+        let base = self
+            .text_at(buffer, revision)
+            .context("Failed to get the base of the change")?;
+        let mut op = changes_to_operation(&base, changes)?;
+        for entry in self.since(revision)? {
+            (op, _) = op
+                .transform(&entry.op)
+                .context("Failed to transform change over concurrent operation")?;
+        }
+        let text = buffer.to_string();
+        let rebased = operation_to_changes(&text, &op);
+        let inverse = op.invert(&text);
+        buffer.apply_changes(&rebased);
+        self.history.push(HistoryEntry { op, inverse });
+        Ok(rebased)
+    }
+}
+
+/// State of a document on a client
+#[derive(Debug, Default)]
+pub struct ClientDocument {
+    /// Last server revision this client knows about
+    revision: Revision,
+    /// The document at `revision`
+    server_text: String,
+    /// The document as seen by the editor
+    text: String,
+    /// Change sent to the server which was not acknowledged yet
+    outstanding: Option<OperationSeq>,
+    /// Local changes made while waiting for `outstanding` to be acknowledged
+    buffer: Option<OperationSeq>,
+}
+
+impl ClientDocument {
+    pub fn new(text: String) -> Self {
+        Self::at_revision(text, 0)
+    }
+
+    /// Creates a document whose content is `text` on the server at `revision`
+    pub fn at_revision(text: String, revision: Revision) -> Self {
+        Self {
+            revision,
+            server_text: text.clone(),
+            text,
+            outstanding: None,
+            buffer: None,
+        }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn revision(&self) -> Revision {
+        self.revision
+    }
+
+    /// The document at [`Self::revision`], without the local changes pending
+    pub fn server_text(&self) -> &str {
+        &self.server_text
+    }
+
+    /// Local changes which were not applied by the server yet, from [`Self::server_text`] to
+    /// [`Self::text`]
+    fn pending(&self) -> anyhow::Result<OperationSeq> {
+        let mut pending = OperationSeq::default();
+        pending.retain(self.server_text.chars().count() as u64);
+        for op in [&self.outstanding, &self.buffer].into_iter().flatten() {
+            pending = pending.compose(op)?;
+        }
+        Ok(pending)
+    }
+
+    /// Converts positions in the editor to positions in [`Self::server_text`]
+    pub fn server_positions(&self, positions: &[Position]) -> anyhow::Result<Vec<Position>> {
+        let inverse = self.pending()?.invert(&self.server_text);
+        Ok(transform_positions(
+            &self.text,
+            &self.server_text,
+            &inverse,
+            positions,
+        ))
+    }
+
+    /// Converts positions in [`Self::server_text`] to positions in the editor
+    pub fn local_positions(&self, positions: &[Position]) -> anyhow::Result<Vec<Position>> {
+        Ok(transform_positions(
+            &self.server_text,
+            &self.text,
+            &self.pending()?,
+            positions,
+        ))
+    }
+
+    /// Records changes made in the editor.
+    /// Returns the changes to send to the server, if nothing is waiting for an acknowledgement.
+    pub fn local_change(
+        &mut self,
+        changes: &[TextDocumentContentChangeEvent],
+    ) -> anyhow::Result<Option<Vec<TextDocumentContentChangeEvent>>> {
+        let op = changes_to_operation(&self.text, changes)?;
+        self.text = op.apply(&self.text)?;
+        if self.outstanding.is_none() {
+            self.outstanding = Some(op);
+            return Ok(Some(changes.to_vec()));
+        }
+        self.buffer = Some(match self.buffer.take() {
+            Some(buffer) => buffer.compose(&op)?,
+            None => op,
+        });
+        Ok(None)
+    }
+
+    /// Marks the outstanding change as applied by the server at `revision`.
+    /// Returns the buffered changes to send next, if any.
+    pub fn acknowledge(
+        &mut self,
+        revision: Revision,
+    ) -> anyhow::Result<Option<Vec<TextDocumentContentChangeEvent>>> {
+        let Some(outstanding) = self.outstanding.take() else {
+            bail!("received an acknowledgement but no change is waiting for one");
+        };
+        self.server_text = outstanding.apply(&self.server_text)?;
+        self.revision = revision;
+        Ok(self.buffer.take().map(|buffer| {
+            let changes = operation_to_changes(&self.server_text, &buffer);
+            self.outstanding = Some(buffer);
+            changes
+        }))
+    }
+
+    /// Applies a change coming from the server, which resulted in `revision`.
+    /// Returns the changes to apply in the editor, rebased over the local pending changes.
+    pub fn remote_change(
+        &mut self,
+        revision: Revision,
+        changes: &[TextDocumentContentChangeEvent],
+    ) -> anyhow::Result<Vec<TextDocumentContentChangeEvent>> {
+        if revision != self.revision + 1 {
+            bail!(
+                "expected the change resulting in revision {} but got revision {revision}",
+                self.revision + 1
+            );
+        }
+        let mut op = changes_to_operation(&self.server_text, changes)?;
+        self.server_text = op.apply(&self.server_text)?;
+        self.revision = revision;
+        for pending in [&mut self.outstanding, &mut self.buffer]
+            .into_iter()
+            .flatten()
+        {
+            let (pending_prime, op_prime) = pending.transform(&op)?;
+            *pending = pending_prime;
+            op = op_prime;
+        }
+        let changes = operation_to_changes(&self.text, &op);
+        self.text = op.apply(&self.text)?;
+        Ok(changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_lsp::lsp_types::{Position, Range, TextDocumentContentChangeEvent};
+    use operational_transform::OperationSeq;
+    use pretty_assertions::assert_eq;
+
+    use super::{ClientDocument, Document, position_to_offset, transform_offset};
+    use crate::buffer::Buffer;
+
+    fn insert(line: u32, character: u32, text: &str) -> Vec<TextDocumentContentChangeEvent> {
+        let position = Position::new(line, character);
+        vec![TextDocumentContentChangeEvent {
+            range: Some(Range::new(position, position)),
+            range_length: None,
+            text: text.to_owned(),
+        }]
+    }
+
+    #[test]
+    fn test_position_to_offset() {
+        let text = "ab\ncd\n";
+        assert_eq!(position_to_offset(text, Position::new(0, 0)), 0);
+        assert_eq!(position_to_offset(text, Position::new(0, 9)), 2);
+        assert_eq!(position_to_offset(text, Position::new(1, 1)), 4);
+        assert_eq!(position_to_offset(text, Position::new(2, 0)), 6);
+        assert_eq!(position_to_offset(text, Position::new(7, 3)), 6);
+    }
+
+    #[test]
+    fn test_transform_offset() {
+        let text = "hello world";
+        // "hello world" -> "hi, world!"
+        let mut op = OperationSeq::default();
+        op.retain(1);
+        op.delete(4);
+        op.insert("i,");
+        op.retain(6);
+        op.insert("!");
+        assert_eq!(op.apply(text).unwrap(), "hi, world!");
+        assert_eq!(transform_offset(&op, 0), 0);
+        // inside the replaced text
+        assert_eq!(transform_offset(&op, 3), 3);
+        assert_eq!(transform_offset(&op, 5), 3);
+        assert_eq!(transform_offset(&op, 11), 10);
+    }
+
+    #[test]
+    fn test_transform_positions() -> anyhow::Result<()> {
+        let mut buffer = Buffer::new("hello\nworld");
+        let mut server = Document::default();
+        let mut client = ClientDocument::new(buffer.to_string());
+        server.apply(&mut buffer, 0, &insert(1, 0, "big "))?;
+        // the cursor after "wor" at revision 0
+        assert_eq!(
+            server.transform_positions(&buffer, 0, &[Position::new(1, 3)])?,
+            vec![Position::new(1, 7)]
+        );
+
+        // the client typed before the cursor, the server did not receive it yet
+        client.local_change(&insert(0, 0, "\n"))?;
+        let local = Position::new(2, 3);
+        let server_position = client.server_positions(&[local])?;
+        assert_eq!(server_position, vec![Position::new(1, 3)]);
+        assert_eq!(client.local_positions(&server_position)?, vec![local]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_server_rebases_concurrent_changes() -> anyhow::Result<()> {
+        let mut buffer = Buffer::new("hello\nworld");
+        let mut document = Document::default();
+        document.apply(&mut buffer, 0, &insert(1, 0, "big "))?;
+        // made without knowing about the first change
+        let rebased = document.apply(&mut buffer, 0, &insert(1, 5, "!"))?;
+        assert_eq!(rebased, insert(1, 9, "!"));
+        assert_eq!(buffer.to_string(), "hello\nbig world!");
+        assert_eq!(document.revision(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_document_at_revision() -> anyhow::Result<()> {
+        let mut buffer = Buffer::new("hello");
+        let mut document = Document::at_revision(5);
+        document.apply(&mut buffer, 5, &insert(0, 5, " world"))?;
+        assert_eq!(document.revision(), 6);
+        assert_eq!(document.text_at(&buffer, 5)?, "hello");
+        assert!(document.apply(&mut buffer, 4, &insert(0, 0, "!")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_clients_converge() -> anyhow::Result<()> {
+        let text = "abc".to_owned();
+        let mut buffer = Buffer::new(&text);
+        let mut server = Document::default();
+        let mut client1 = ClientDocument::new(text.clone());
+        let mut client2 = ClientDocument::new(text);
+
+        // both clients type at the same time
+        let sent1 = client1.local_change(&insert(0, 0, "1"))?.unwrap();
+        let sent2 = client2.local_change(&insert(0, 3, "2"))?.unwrap();
+        // client 1 keeps typing while its first change is in flight
+        assert_eq!(client1.local_change(&insert(0, 1, "1"))?, None);
+
+        let rebased1 = server.apply(&mut buffer, client1.revision(), &sent1)?;
+        let rebased2 = server.apply(&mut buffer, client2.revision(), &sent2)?;
+
+        let sent1 = client1.acknowledge(1)?.unwrap();
+        client1.remote_change(2, &rebased2)?;
+        client2.remote_change(1, &rebased1)?;
+        assert_eq!(client2.acknowledge(2)?, None);
+
+        let rebased1 = server.apply(&mut buffer, client1.revision(), &sent1)?;
+        assert_eq!(client1.acknowledge(3)?, None);
+        client2.remote_change(3, &rebased1)?;
+
+        assert_eq!(buffer.to_string(), "11abc2");
+        assert_eq!(client1.text(), buffer.to_string());
+        assert_eq!(client2.text(), buffer.to_string());
+
+        // the change resulting in revision 4 is missing
+        assert!(client2.remote_change(5, &insert(0, 0, "3")).is_err());
+        Ok(())
+    }
+}
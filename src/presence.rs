@@ -0,0 +1,242 @@
+//! Cursors and selections of the users editing the shared documents.
+//!
+//! Editors don't tell language servers where the cursor is, but they ask for the
+//! `textDocument/documentHighlight` of the symbol under it whenever it moves, and for the
+//! `textDocument/codeAction` of the selection. The client turns those requests into a
+//! [`Presence`] sent to its peers, and answers the highlight requests with the cursors of the
+//! peers. Editors with a codlab plugin can listen to [`PresenceNotification`] instead.
+//!
+//! The editor can also follow a peer: the client asks it to show the document and the selection
+//! of the peer with `window/showDocument` whenever they move.
+
+use std::collections::HashMap;
+
+use async_lsp::lsp_types::{
+    DocumentHighlight, DocumentHighlightKind, Position, Range, TextDocumentContentChangeEvent, Url,
+    notification::Notification,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{messages::Presence, ot};
+
+/// Sent to the editor when the cursor or the selections of a peer moved
+pub enum PresenceNotification {}
+
+impl Notification for PresenceNotification {
+    type Params = Presence;
+    const METHOD: &'static str = "codlab/presence";
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserLeftParams {
+    pub user: Uuid,
+}
+
+/// Sent to the editor when a peer left, its cursor should not be shown anymore
+pub enum UserLeftNotification {}
+
+impl Notification for UserLeftNotification {
+    type Params = UserLeftParams;
+    const METHOD: &'static str = "codlab/userLeft";
+}
+
+/// Replaces the positions of a presence, the cursor followed by the bounds of each selection
+pub fn map_positions(
+    presence: &mut Presence,
+    f: impl FnOnce(&[Position]) -> anyhow::Result<Vec<Position>>,
+) -> anyhow::Result<()> {
+    let positions: Vec<_> = std::iter::once(presence.cursor)
+        .chain(
+            presence
+                .selections
+                .iter()
+                .flat_map(|range| [range.start, range.end]),
+        )
+        .collect();
+    let positions = f(&positions)?;
+    presence.cursor = positions[0];
+    presence.selections = positions[1..]
+        .chunks_exact(2)
+        .map(|bounds| Range::new(bounds[0], bounds[1]))
+        .collect();
+    Ok(())
+}
+
+/// Presences of the peers, with positions in the documents as seen by the editor
+#[derive(Debug, Default)]
+pub struct Presences {
+    by_user: HashMap<Uuid, Presence>,
+    /// Name or id of the peer followed by the editor
+    following: Option<String>,
+}
+
+impl Presences {
+    pub fn update(&mut self, presence: Presence) {
+        self.by_user.insert(presence.user, presence);
+    }
+
+    pub fn remove(&mut self, user: &Uuid) -> Option<Presence> {
+        self.by_user.remove(user)
+    }
+
+    /// Forgets the presences of the peers, the followed one is still followed if it comes back
+    pub fn clear(&mut self) {
+        self.by_user.clear();
+    }
+
+    /// Follows the peer with this name or id, or no one
+    pub fn follow(&mut self, user: Option<String>) {
+        self.following = user;
+    }
+
+    pub fn following(&self) -> Option<&str> {
+        self.following.as_deref()
+    }
+
+    /// Whether the presence is the one of the followed peer
+    pub fn is_followed(&self, presence: &Presence) -> bool {
+        self.following.as_deref().is_some_and(|following| {
+            presence.username.as_deref() == Some(following)
+                || presence.user.to_string() == following
+        })
+    }
+
+    /// Last known presence of the followed peer
+    pub fn followed(&self) -> Option<&Presence> {
+        self.by_user
+            .values()
+            .find(|presence| self.is_followed(presence))
+    }
+
+    /// Moves the positions in `uri` through changes made to its content, `before`
+    pub fn transform(
+        &mut self,
+        uri: &Url,
+        before: &str,
+        changes: &[TextDocumentContentChangeEvent],
+    ) -> anyhow::Result<()> {
+        let mut presences = self
+            .by_user
+            .values_mut()
+            .filter(|presence| presence.uri == *uri)
+            .peekable();
+        if presences.peek().is_none() {
+            return Ok(());
+        }
+        let op = ot::changes_to_operation(before, changes)?;
+        let after = op.apply(before)?;
+        for presence in presences {
+            map_positions(presence, |positions| {
+                Ok(ot::transform_positions(before, &after, &op, positions))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Highlights showing the cursors and the selections of the peers in `uri`
+    pub fn highlights(&self, uri: &Url) -> Vec<DocumentHighlight> {
+        self.by_user
+            .values()
+            .filter(|presence| presence.uri == *uri)
+            .flat_map(|presence| {
+                let cursor = presence.cursor;
+                // an empty range would not be visible
+                let after_cursor = Position::new(cursor.line, cursor.character + 1);
+                std::iter::once(Range::new(cursor, after_cursor))
+                    .chain(presence.selections.iter().copied())
+                    .filter(|range| range.start != range.end)
+            })
+            .map(|range| DocumentHighlight {
+                range,
+                kind: Some(DocumentHighlightKind::TEXT),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_lsp::lsp_types::{Position, Range, TextDocumentContentChangeEvent, Url};
+    use pretty_assertions::assert_eq;
+    use uuid::Uuid;
+
+    use super::Presences;
+    use crate::messages::Presence;
+
+    #[test]
+    fn test_presences_follow_changes() -> anyhow::Result<()> {
+        let uri = Url::parse("file:///tmp/src/lib.rs").unwrap();
+        let other = Url::parse("file:///tmp/src/main.rs").unwrap();
+        let presence = |uri: &Url| Presence {
+            user: Uuid::new_v4(),
+            username: None,
+            uri: uri.clone(),
+            revision: 0,
+            cursor: Position::new(1, 2),
+            selections: vec![Range::new(Position::new(0, 1), Position::new(1, 2))],
+        };
+        let mut presences = Presences::default();
+        let moved = presence(&uri);
+        let unchanged = presence(&other);
+        presences.update(moved.clone());
+        presences.update(unchanged.clone());
+
+        presences.transform(
+            &uri,
+            "hello\nworld",
+            &[TextDocumentContentChangeEvent {
+                range: Some(Range::new(Position::new(0, 0), Position::new(0, 0))),
+                range_length: None,
+                text: "ah\n".to_owned(),
+            }],
+        )?;
+
+        assert_eq!(
+            presences.remove(&moved.user).unwrap(),
+            Presence {
+                cursor: Position::new(2, 2),
+                selections: vec![Range::new(Position::new(1, 1), Position::new(2, 2))],
+                ..moved
+            }
+        );
+        assert_eq!(presences.highlights(&uri), vec![]);
+        assert_eq!(presences.highlights(&other).len(), 2);
+        assert_eq!(presences.remove(&unchanged.user).unwrap(), unchanged);
+        Ok(())
+    }
+
+    #[test]
+    fn test_follow() {
+        let uri = Url::parse("file:///tmp/src/lib.rs").unwrap();
+        let alice = Presence {
+            user: Uuid::new_v4(),
+            username: Some("alice".to_owned()),
+            uri,
+            revision: 0,
+            cursor: Position::new(0, 0),
+            selections: vec![],
+        };
+        let anonymous = Presence {
+            user: Uuid::new_v4(),
+            username: None,
+            ..alice.clone()
+        };
+        let mut presences = Presences::default();
+        presences.update(alice.clone());
+        presences.update(anonymous.clone());
+        assert_eq!(presences.followed(), None);
+
+        presences.follow(Some("alice".to_owned()));
+        assert!(presences.is_followed(&alice));
+        assert!(!presences.is_followed(&anonymous));
+        assert_eq!(presences.followed(), Some(&alice));
+
+        // peers without a name are followed by id
+        presences.follow(Some(anonymous.user.to_string()));
+        assert_eq!(presences.followed(), Some(&anonymous));
+
+        presences.follow(None);
+        assert_eq!(presences.followed(), None);
+    }
+}
@@ -0,0 +1,200 @@
+//! Recording of the changes relayed by the server, one JSON object per line, read back by the
+//! `codlab-replay` binary to rebuild the documents at any revision.
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::Write as _,
+    path::Path,
+};
+
+use anyhow::{Context, bail};
+use async_lsp::lsp_types::Url;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    buffer::Buffer,
+    common::timestamp,
+    crdt,
+    messages::{CommonMessage, Edit, Revision},
+};
+
+/// A change relayed by the server, as rebased and broadcasted to the peers of its author
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecordedChange {
+    pub session: String,
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    pub change: CommonMessage,
+}
+
+/// Appends the changes relayed by the server to a file
+#[derive(Debug)]
+pub struct Recorder {
+    file: File,
+}
+
+impl Recorder {
+    pub fn new(path: &Path) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open recording {}", path.display()))?;
+        Ok(Self { file })
+    }
+
+    /// Appends a line made by [`line`]
+    pub fn append(&mut self, line: &str) -> anyhow::Result<()> {
+        self.file
+            .write_all(line.as_bytes())
+            .context("Failed to record change")
+    }
+}
+
+/// Line of the recording of a change relayed in `session` now
+pub fn line(session: &str, change: &CommonMessage) -> anyhow::Result<String> {
+    #[derive(Serialize)]
+    struct Borrowed<'a> {
+        session: &'a str,
+        timestamp: u64,
+        change: &'a CommonMessage,
+    }
+    let mut line = serde_json::to_string(&Borrowed {
+        session,
+        timestamp: timestamp(),
+        change,
+    })?;
+    line.push('\n');
+    Ok(line)
+}
+
+pub fn read(path: &Path) -> anyhow::Result<Vec<RecordedChange>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read recording {}", path.display()))?;
+    content
+        .lines()
+        .enumerate()
+        .map(|(index, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("Invalid change at line {}", index + 1))
+        })
+        .collect()
+}
+
+/// Turns the changes recorded in `session` into the edits of each document, in the order they
+/// were relayed. The recording must have started with the session, as the edits are applied
+/// to empty documents.
+pub fn edits(changes: Vec<RecordedChange>, session: &str) -> anyhow::Result<Vec<(Url, Edit)>> {
+    let mut revisions = HashMap::<Url, Revision>::new();
+    let mut crdts = HashMap::<Url, crdt::Document>::new();
+    let mut edits = Vec::new();
+    for recorded in changes
+        .into_iter()
+        .filter(|recorded| recorded.session == session)
+    {
+        let uri = recorded.change.uri().clone();
+        let expected = revisions.get(&uri).copied().unwrap_or_default() + 1;
+        let (author, revision, changes) = match recorded.change {
+            CommonMessage::Change(change) => {
+                if change.revision != expected {
+                    bail!(
+                        "{uri} jumps to revision {} instead of {expected}, the recording must start with the session",
+                        change.revision
+                    );
+                }
+                (
+                    change.author,
+                    change.revision,
+                    change.change.content_changes,
+                )
+            }
+            CommonMessage::CrdtChange(change) => {
+                let changes = crdts
+                    .entry(uri.clone())
+                    .or_default()
+                    .remote_change(&change.changes)
+                    .with_context(|| format!("Failed to apply revision {expected} of {uri}"))?;
+                (change.author, expected, changes)
+            }
+        };
+        revisions.insert(uri.clone(), revision);
+        edits.push((
+            uri,
+            Edit {
+                revision,
+                author,
+                timestamp: recorded.timestamp,
+                changes,
+            },
+        ));
+    }
+    Ok(edits)
+}
+
+/// Content of a document after the edits up to `revision`, `edits` being all of its edits
+pub fn text_at<'a>(edits: impl IntoIterator<Item = &'a Edit>, revision: Revision) -> String {
+    let mut buffer = Buffer::default();
+    for edit in edits
+        .into_iter()
+        .take_while(|edit| edit.revision <= revision)
+    {
+        buffer.apply_changes(&edit.changes);
+    }
+    buffer.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use async_lsp::lsp_types::{
+        DidChangeTextDocumentParams, Position, Range, TextDocumentContentChangeEvent, Url,
+        VersionedTextDocumentIdentifier,
+    };
+    use pretty_assertions::assert_eq;
+    use uuid::Uuid;
+
+    use super::{RecordedChange, edits, text_at};
+    use crate::messages::{Change, CommonMessage};
+
+    fn recorded(session: &str, revision: u64, text: &str) -> RecordedChange {
+        let uri = Url::parse("file:///tmp/src/lib.rs").unwrap();
+        RecordedChange {
+            session: session.to_owned(),
+            timestamp: 0,
+            change: CommonMessage::Change(Change {
+                id: Uuid::new_v4(),
+                author: Some("alice".to_owned()),
+                revision,
+                change: DidChangeTextDocumentParams {
+                    text_document: VersionedTextDocumentIdentifier::new(uri, 0),
+                    content_changes: vec![TextDocumentContentChangeEvent {
+                        range: Some(Range::new(Position::new(0, 0), Position::new(0, 0))),
+                        range_length: None,
+                        text: text.to_owned(),
+                    }],
+                },
+            }),
+        }
+    }
+
+    #[test]
+    fn test_replay() -> anyhow::Result<()> {
+        let changes = vec![
+            recorded("pairing", 1, "world"),
+            recorded("other", 1, "ignored"),
+            recorded("pairing", 2, "hello "),
+        ];
+        let edits: Vec<_> = edits(changes, "pairing")?
+            .into_iter()
+            .map(|(_, edit)| edit)
+            .collect();
+        assert_eq!(edits.len(), 2);
+        assert_eq!(text_at(&edits, 0), "");
+        assert_eq!(text_at(&edits, 1), "world");
+        assert_eq!(text_at(&edits, 2), "hello world");
+
+        // the first change was not recorded
+        assert!(super::edits(vec![recorded("pairing", 2, "hello ")], "pairing").is_err());
+        Ok(())
+    }
+}
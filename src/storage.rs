@@ -0,0 +1,253 @@
+//! Persistence of the sessions, so that they survive restarts of the server.
+//!
+//! Each session has a directory in the storage directory, holding a snapshot of its documents
+//! and a log of the edits applied since then, one JSON object per line. The server appends to
+//! the log as it applies changes, and replaces the snapshot once the log grew long enough.
+//! Edits of the log which are already in the snapshot, left by a crash while replacing it, are
+//! skipped when loading the session. The snapshot only keeps the content of the documents, so
+//! the history of the edits before it is lost.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write as _,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use async_lsp::lsp_types::Url;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::messages::{Edit, Revision, SyncEngine};
+
+const SNAPSHOT_FILE: &str = "snapshot.json";
+const LOG_FILE: &str = "log.jsonl";
+/// Number of edits logged after which the snapshot is replaced
+pub const SNAPSHOT_INTERVAL: usize = 1000;
+
+/// A document as saved in a snapshot
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredDocument {
+    pub uri: Url,
+    pub text: String,
+    /// Number of edits applied to the document, the ones of the log come after it
+    pub revision: Revision,
+    /// Encoded Automerge document, used with [`SyncEngine::Crdt`]
+    pub crdt: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionSnapshot {
+    pub engine: SyncEngine,
+    pub documents: Vec<StoredDocument>,
+}
+
+/// An edit appended to the log of a session
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub uri: Url,
+    pub edit: Edit,
+    /// Encoded Automerge changes, used with [`SyncEngine::Crdt`]
+    pub crdt: Option<Vec<u8>>,
+}
+
+/// Directory holding the sessions
+#[derive(Debug, Clone)]
+pub struct Storage {
+    dir: PathBuf,
+}
+
+impl Storage {
+    pub fn new(dir: &Path) -> anyhow::Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create storage directory {}", dir.display()))?;
+        Ok(Self {
+            dir: dir.to_owned(),
+        })
+    }
+
+    /// Names of the sessions saved in the storage directory
+    pub fn sessions(&self) -> anyhow::Result<Vec<String>> {
+        let mut names = Vec::new();
+        let entries = fs::read_dir(&self.dir)
+            .with_context(|| format!("Failed to read storage directory {}", self.dir.display()))?;
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let dir_name = entry.file_name();
+            match dir_name.to_str().and_then(decode_name) {
+                Some(name) => names.push(name),
+                None => warn!(
+                    "Skipping {}, it is not the directory of a session",
+                    entry.path().display()
+                ),
+            }
+        }
+        Ok(names)
+    }
+
+    pub fn open(&self, session: &str) -> anyhow::Result<SessionStorage> {
+        let dir = self.dir.join(encode_name(session));
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create session directory {}", dir.display()))?;
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(LOG_FILE))
+            .with_context(|| format!("Failed to open the log of session {session}"))?;
+        Ok(SessionStorage { dir, log })
+    }
+}
+
+/// Snapshot and log of a session
+#[derive(Debug)]
+pub struct SessionStorage {
+    dir: PathBuf,
+    log: File,
+}
+
+impl SessionStorage {
+    /// Reads the snapshot of the session, if there is one, and the edits logged after it
+    pub fn load(&self) -> anyhow::Result<(Option<SessionSnapshot>, Vec<LogEntry>)> {
+        let path = self.dir.join(SNAPSHOT_FILE);
+        let snapshot = match fs::read_to_string(&path) {
+            Ok(content) => Some(
+                serde_json::from_str(&content)
+                    .with_context(|| format!("Failed to parse snapshot {}", path.display()))?,
+            ),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to read {}", path.display()));
+            }
+        };
+        let path = self.dir.join(LOG_FILE);
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut entries = Vec::new();
+        for line in content.lines() {
+            match serde_json::from_str(line) {
+                Ok(entry) => entries.push(entry),
+                // the server stopped while writing the last line
+                Err(_) if entries.len() + 1 == content.lines().count() => break,
+                Err(err) => {
+                    return Err(err).with_context(|| format!("Failed to parse {}", path.display()));
+                }
+            }
+        }
+        Ok((snapshot, entries))
+    }
+
+    /// Appends an edit to the log, the snapshot should be replaced every [`SNAPSHOT_INTERVAL`]
+    /// edits, see [`Self::save`]
+    pub fn append(&mut self, entry: &LogEntry) -> anyhow::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        self.log
+            .write_all(line.as_bytes())
+            .context("Failed to append to the log")
+    }
+
+    /// Replaces the snapshot and empties the log
+    pub fn save(&mut self, snapshot: &SessionSnapshot) -> anyhow::Result<()> {
+        let path = self.dir.join(SNAPSHOT_FILE);
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec(snapshot)?)
+            .with_context(|| format!("Failed to write {}", tmp.display()))?;
+        // renaming is atomic, a crash leaves either the old or the new snapshot
+        fs::rename(&tmp, &path).with_context(|| format!("Failed to replace {}", path.display()))?;
+        self.log.set_len(0).context("Failed to empty the log")
+    }
+}
+
+/// Escapes a session name into a directory name, sessions can be named after any URL path
+fn encode_name(name: &str) -> String {
+    name.bytes()
+        .map(|byte| match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => (byte as char).to_string(),
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+/// Reverts [`encode_name`], `None` for the directories it did not name
+fn decode_name(dir_name: &str) -> Option<String> {
+    let mut bytes = Vec::new();
+    let mut rest = dir_name.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else if byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' {
+            bytes.push(byte);
+            rest = tail;
+        } else {
+            return None;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use async_lsp::lsp_types::Url;
+    use pretty_assertions::assert_eq;
+    use uuid::Uuid;
+
+    use super::{LogEntry, SessionSnapshot, Storage, decode_name, encode_name};
+    use crate::messages::{Edit, SyncEngine};
+
+    #[test]
+    fn test_session_names() {
+        for name in ["default", "my-team", "a/b c", "..", "été"] {
+            let encoded = encode_name(name);
+            assert!(!encoded.contains(['/', '.']), "{encoded}");
+            assert_eq!(decode_name(&encoded).as_deref(), Some(name));
+        }
+        assert_eq!(decode_name("lost+found"), None);
+    }
+
+    #[test]
+    fn test_log_survives_snapshots() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("codlab-storage-{}", Uuid::new_v4()));
+        let storage = Storage::new(&dir)?;
+        let uri = Url::parse("file:///tmp/src/lib.rs").unwrap();
+        let entry = |revision| LogEntry {
+            uri: uri.clone(),
+            edit: Edit {
+                revision,
+                author: None,
+                timestamp: 0,
+                changes: Vec::new(),
+            },
+            crdt: None,
+        };
+
+        let mut session = storage.open("a/b")?;
+        session.append(&entry(1))?;
+        session.save(&SessionSnapshot {
+            engine: SyncEngine::Ot,
+            documents: Vec::new(),
+        })?;
+        session.append(&entry(2))?;
+        session.append(&entry(3))?;
+        drop(session);
+        // left there by someone else
+        std::fs::create_dir(dir.join("lost+found"))?;
+
+        assert_eq!(storage.sessions()?, vec!["a/b".to_owned()]);
+        let (snapshot, entries) = storage.open("a/b")?.load()?;
+        assert_eq!(snapshot.unwrap().engine, SyncEngine::Ot);
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| entry.edit.revision)
+                .collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
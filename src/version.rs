@@ -0,0 +1,161 @@
+//! Ordering of the `didChange` notifications of a document using their version.
+//!
+//! The version of a document increases after each change, so a notification with a version
+//! which was already seen is a duplicate or arrived out of order, and a gap in the versions
+//! means a notification is missing or still on its way. Helix was seen sending changes out of
+//! order ("hello world" became "hellow orld" on the other side).
+
+use std::collections::{BTreeMap, VecDeque};
+
+use async_lsp::lsp_types::DidChangeTextDocumentParams;
+
+/// Number of applied notifications kept to recognize duplicates
+const APPLIED_HISTORY: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub enum Ordered {
+    /// Notifications to apply, in order
+    Ready(Vec<DidChangeTextDocumentParams>),
+    /// Kept until the notification with the `missing` version arrives
+    Buffered { missing: i32 },
+    /// Already applied
+    Duplicate,
+    /// Arrived after newer changes were applied, the document is out of sync
+    Desync,
+}
+
+#[derive(Debug)]
+pub struct VersionTracker {
+    /// Version of the last notification applied
+    version: i32,
+    /// Notifications received before a missing version, by version
+    buffered: BTreeMap<i32, DidChangeTextDocumentParams>,
+    /// Last notifications applied, to tell duplicates from late ones
+    applied: VecDeque<DidChangeTextDocumentParams>,
+}
+
+impl VersionTracker {
+    /// Starts tracking a document opened at `version`
+    pub fn new(version: i32) -> Self {
+        Self {
+            version,
+            buffered: BTreeMap::new(),
+            applied: VecDeque::new(),
+        }
+    }
+
+    pub fn version(&self) -> i32 {
+        self.version
+    }
+
+    pub fn push(&mut self, params: DidChangeTextDocumentParams) -> Ordered {
+        let version = params.text_document.version;
+        if version <= self.version || self.buffered.contains_key(&version) {
+            let duplicate = self
+                .applied
+                .iter()
+                .chain(self.buffered.values())
+                .any(|applied| *applied == params);
+            return if duplicate {
+                Ordered::Duplicate
+            } else {
+                Ordered::Desync
+            };
+        }
+        if version > self.version + 1 {
+            self.buffered.insert(version, params);
+            return Ordered::Buffered {
+                missing: self.version + 1,
+            };
+        }
+        let mut ready = vec![params];
+        let mut next = version + 1;
+        while let Some(params) = self.buffered.remove(&next) {
+            ready.push(params);
+            next += 1;
+        }
+        self.applied(&ready);
+        Ordered::Ready(ready)
+    }
+
+    /// Stops waiting for the missing versions, as editors are allowed to skip some.
+    /// Returns the buffered notifications, in order.
+    pub fn flush(&mut self) -> Vec<DidChangeTextDocumentParams> {
+        let ready: Vec<_> = std::mem::take(&mut self.buffered).into_values().collect();
+        self.applied(&ready);
+        ready
+    }
+
+    fn applied(&mut self, params: &[DidChangeTextDocumentParams]) {
+        if let Some(last) = params.last() {
+            self.version = last.text_document.version;
+        }
+        self.applied.extend(params.iter().cloned());
+        while self.applied.len() > APPLIED_HISTORY {
+            self.applied.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_lsp::lsp_types::{
+        DidChangeTextDocumentParams, Position, Range, TextDocumentContentChangeEvent, Url,
+        VersionedTextDocumentIdentifier,
+    };
+    use pretty_assertions::assert_eq;
+
+    use super::{Ordered, VersionTracker};
+
+    fn change(version: i32, character: u32, text: &str) -> DidChangeTextDocumentParams {
+        let position = Position::new(0, character);
+        DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier::new(
+                Url::parse("file:///tmp/src/lib.rs").unwrap(),
+                version,
+            ),
+            content_changes: vec![TextDocumentContentChangeEvent {
+                range: Some(Range::new(position, position)),
+                range_length: None,
+                text: text.to_owned(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_reordered_changes() {
+        let mut tracker = VersionTracker::new(0);
+        assert_eq!(
+            tracker.push(change(1, 0, "hello")),
+            Ordered::Ready(vec![change(1, 0, "hello")])
+        );
+        // the `w` was sent before the space
+        assert_eq!(
+            tracker.push(change(3, 6, "w")),
+            Ordered::Buffered { missing: 2 }
+        );
+        assert_eq!(
+            tracker.push(change(2, 5, " ")),
+            Ordered::Ready(vec![change(2, 5, " "), change(3, 6, "w")])
+        );
+        assert_eq!(tracker.version(), 3);
+    }
+
+    #[test]
+    fn test_duplicated_and_late_changes() {
+        let mut tracker = VersionTracker::new(0);
+        tracker.push(change(1, 0, "a"));
+        assert_eq!(tracker.push(change(1, 0, "a")), Ordered::Duplicate);
+        assert_eq!(tracker.push(change(1, 0, "b")), Ordered::Desync);
+
+        assert_eq!(
+            tracker.push(change(3, 1, "c")),
+            Ordered::Buffered { missing: 2 }
+        );
+        assert_eq!(tracker.push(change(3, 1, "c")), Ordered::Duplicate);
+        assert_eq!(tracker.flush(), vec![change(3, 1, "c")]);
+        assert_eq!(tracker.version(), 3);
+        // too late, the change after it was already applied
+        assert_eq!(tracker.push(change(2, 1, "b")), Ordered::Desync);
+    }
+}
@@ -0,0 +1,202 @@
+//! Workspace-relative URIs.
+//!
+//! Peers rarely have a project at the same path, so the clients send the URIs of the documents
+//! of their workspace relative to its root, as `codlab:///src/lib.rs`, and map the URIs they
+//! receive back to their own workspace. Documents outside of the workspace keep their URI.
+//!
+//! The URIs come from the peers, so once the root is known the ones which are not relative to
+//! the workspace, or could lead out of it once decoded, are refused, and only the files of the
+//! workspace are read and written to.
+
+use std::path::PathBuf;
+
+use async_lsp::lsp_types::{InitializeParams, Url};
+
+/// Scheme of the workspace-relative URIs exchanged between the clients and the server
+pub const SCHEME: &str = "codlab";
+
+/// Root of the workspace open in the editor
+#[derive(Debug, Default, Clone)]
+pub struct Workspace {
+    /// Always ends with a `/`, unknown if the editor opened single files
+    root: Option<Url>,
+}
+
+impl Workspace {
+    pub fn new(root: Option<Url>) -> Self {
+        let root = root.map(|mut root| {
+            if !root.path().ends_with('/') {
+                root.set_path(&format!("{}/", root.path()));
+            }
+            root
+        });
+        Self { root }
+    }
+
+    /// Workspace of the editor, its first folder if it has several
+    #[allow(deprecated)]
+    pub fn from_params(params: &InitializeParams) -> Self {
+        let folder = params
+            .workspace_folders
+            .as_ref()
+            .and_then(|folders| folders.first())
+            .map(|folder| folder.uri.clone());
+        Self::new(folder.or_else(|| params.root_uri.clone()))
+    }
+
+    pub fn root(&self) -> Option<&Url> {
+        self.root.as_ref()
+    }
+
+    /// Path of a local document relative to the root, percent-encoded
+    pub fn relative_path<'a>(&self, uri: &'a Url) -> Option<&'a str> {
+        uri.as_str().strip_prefix(self.root.as_ref()?.as_str())
+    }
+
+    /// URI of a local document on the wire
+    pub fn to_wire(&self, uri: &Url) -> Url {
+        match self.relative_path(uri) {
+            Some(relative) => Url::parse(&format!("{SCHEME}:///{relative}"))
+                .expect("a path of a valid URI to make a valid URI"),
+            None => uri.clone(),
+        }
+    }
+
+    /// Local URI of a document received from the wire, kept as is if this workspace has no
+    /// root. `None` if it has one but the URI is not relative to the workspace of the peer, or
+    /// its path would leave the workspace.
+    pub fn to_local(&self, uri: &Url) -> Option<Url> {
+        let Some(root) = self.root.as_ref() else {
+            return Some(uri.clone());
+        };
+        if uri.scheme() != SCHEME || !stays_inside(uri.path()) {
+            return None;
+        }
+        // `./` so that a first segment containing a `:` is not taken for a scheme
+        let relative = format!(".{}", uri.path());
+        root.join(&relative)
+            .ok()
+            .filter(|local| self.relative_path(local).is_some())
+    }
+
+    /// Path of a file of the workspace, `None` for the documents outside of it, including the
+    /// ones whose path would leave it once decoded
+    pub fn file_path(&self, uri: &Url) -> Option<PathBuf> {
+        if !stays_inside(self.relative_path(uri)?) {
+            return None;
+        }
+        uri.to_file_path().ok()
+    }
+}
+
+/// Whether a percent-encoded path keeps going down once decoded, [`Url`] resolves the dot
+/// segments it sees but not the ones made of encoded separators
+fn stays_inside(path: &str) -> bool {
+    path.split('/').all(|segment| {
+        let segment = segment.to_ascii_lowercase();
+        !segment.contains("%2f")
+            && !segment.contains("%5c")
+            && !segment.contains('\\')
+            && !matches!(segment.replace("%2e", ".").as_str(), "." | "..")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use async_lsp::lsp_types::{InitializeParams, Url, WorkspaceFolder};
+    use pretty_assertions::assert_eq;
+
+    use super::Workspace;
+
+    fn url(url: &str) -> Url {
+        Url::parse(url).unwrap()
+    }
+
+    #[test]
+    fn test_peers_with_different_roots() {
+        let alice = Workspace::new(Some(url("file:///home/alice/proj")));
+        let bob = Workspace::new(Some(url("file:///home/bob/work/proj/")));
+
+        let local = url("file:///home/alice/proj/src/lib%20a.rs");
+        assert_eq!(alice.relative_path(&local), Some("src/lib%20a.rs"));
+        let wire = alice.to_wire(&local);
+        assert_eq!(wire, url("codlab:///src/lib%20a.rs"));
+        assert_eq!(
+            bob.to_local(&wire),
+            Some(url("file:///home/bob/work/proj/src/lib%20a.rs"))
+        );
+        assert_eq!(
+            bob.to_local(&url("codlab:///a:b.rs")),
+            Some(url("file:///home/bob/work/proj/a:b.rs"))
+        );
+
+        // outside of the workspace, or with no workspace
+        let outside = url("file:///home/alice/project/main.rs");
+        assert_eq!(alice.relative_path(&outside), None);
+        assert_eq!(alice.to_wire(&outside), outside);
+        assert_eq!(bob.to_local(&outside), None);
+        assert_eq!(bob.to_local(&url("file:///home/bob/.bashrc")), None);
+        assert_eq!(Workspace::default().to_local(&wire), Some(wire));
+        assert_eq!(Workspace::default().to_local(&outside), Some(outside));
+    }
+
+    #[test]
+    fn test_paths_leaving_the_workspace() {
+        let bob = Workspace::new(Some(url("file:///home/bob/proj")));
+        for wire in [
+            "codlab:///a%2F..%2F..%2F.bashrc",
+            "codlab:///a%2f..%2f..%2f.bashrc",
+            "codlab:///..%5C..%5C.bashrc",
+        ] {
+            assert_eq!(bob.to_local(&url(wire)), None, "{wire}");
+        }
+        // resolved when parsing
+        assert_eq!(
+            bob.to_local(&url("codlab:///../../.bashrc")),
+            Some(url("file:///home/bob/proj/.bashrc"))
+        );
+
+        assert_eq!(
+            bob.file_path(&url("file:///home/bob/proj/src/lib%20a.rs")),
+            Some(PathBuf::from("/home/bob/proj/src/lib a.rs"))
+        );
+        for uri in [
+            "file:///home/bob/proj/a%2F..%2F..%2F.bashrc",
+            "file:///home/bob/.bashrc",
+            "codlab:///src/lib.rs",
+        ] {
+            assert_eq!(bob.file_path(&url(uri)), None, "{uri}");
+        }
+        assert_eq!(
+            Workspace::default().file_path(&url("file:///home/bob/proj/src/lib.rs")),
+            None
+        );
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_from_params() {
+        let params = InitializeParams {
+            root_uri: Some(url("file:///root")),
+            workspace_folders: Some(vec![WorkspaceFolder {
+                uri: url("file:///folder"),
+                name: "folder".to_owned(),
+            }]),
+            ..InitializeParams::default()
+        };
+        assert_eq!(
+            Workspace::from_params(&params).root(),
+            Some(&url("file:///folder/"))
+        );
+        let params = InitializeParams {
+            workspace_folders: None,
+            ..params
+        };
+        assert_eq!(
+            Workspace::from_params(&params).root(),
+            Some(&url("file:///root/"))
+        );
+    }
+}
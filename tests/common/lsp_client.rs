@@ -2,27 +2,30 @@
 use std::ops::ControlFlow;
 use std::process::{Command, Stdio};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use assert_cmd::cargo::CommandCargoExt as _;
 use async_lsp::concurrency::ConcurrencyLayer;
 use async_lsp::lsp_types::request::ApplyWorkspaceEdit;
 use async_lsp::lsp_types::{
     ApplyWorkspaceEditParams, ApplyWorkspaceEditResponse, ClientCapabilities,
-    DidChangeTextDocumentParams, DidOpenTextDocumentParams, InitializeParams, InitializedParams,
-    WindowClientCapabilities,
+    DidChangeTextDocumentParams, DidOpenTextDocumentParams, GeneralClientCapabilities,
+    InitializeParams, InitializedParams, WindowClientCapabilities,
 };
 use async_lsp::panic::CatchUnwindLayer;
 use async_lsp::router::Router;
 use async_lsp::tracing::TracingLayer;
 use async_lsp::{LanguageClient, LanguageServer, ResponseError, ServerSocket};
 use async_process::Child;
-use codlab::change_event_to_workspace_edit;
+use codlab::{change_event_to_workspace_edit, encoding::PositionEncoding};
 use tokio::task::JoinHandle;
 use tower::ServiceBuilder;
 use tracing::{debug, info};
 
 struct ClientState {
     document: Arc<Mutex<Vec<String>>>,
+    /// Of the positions in the edits
+    encoding: PositionEncoding,
 }
 
 impl LanguageClient for ClientState {
@@ -31,24 +34,19 @@ impl LanguageClient for ClientState {
 }
 
 impl ClientState {
-    fn new_router(document: Arc<Mutex<Vec<String>>>) -> Router<Self> {
-        let mut router = Router::from_language_client(ClientState { document });
+    fn new_router(document: Arc<Mutex<Vec<String>>>, encoding: PositionEncoding) -> Router<Self> {
+        let mut router = Router::from_language_client(ClientState { document, encoding });
         router.event(Self::on_stop);
         router.event(Self::on_local_change);
         router.request::<ApplyWorkspaceEdit, _>(|state, params| {
-            info!(
-                "Received apply edit: `{}`",
-                params
-                    .edit
-                    .changes
-                    .as_ref()
-                    .unwrap()
-                    .values()
-                    .next()
-                    .unwrap()[0]
-                    .new_text
-            );
-            state.apply_edits_impl(params);
+            // files created for the documents shared by peers are ignored, there is only one
+            if let Some(changes) = &params.edit.changes {
+                info!(
+                    "Received apply edit: `{}`",
+                    changes.values().next().unwrap()[0].new_text
+                );
+                state.apply_edits_impl(params);
+            }
             async move {
                 Ok(ApplyWorkspaceEditResponse {
                     applied: true,
@@ -84,13 +82,14 @@ impl ClientState {
             let mut document = self.document.lock().unwrap();
             let position = change.range.start;
             if let Some(line) = document.get_mut(position.line as usize) {
-                if position.character as usize > line.len() {
-                    line.insert_str(
-                        line.len(),
-                        &" ".repeat(position.character as usize - line.len()),
-                    );
+                let index =
+                    self.encoding
+                        .convert(PositionEncoding::Utf8, line.chars(), position.character)
+                        as usize;
+                if index > line.len() {
+                    line.insert_str(line.len(), &" ".repeat(index - line.len()));
                 }
-                line.insert_str(position.character as usize, &change.new_text);
+                line.insert_str(index, &change.new_text);
             } else {
                 // add empty lines up to the change
                 for _ in 0..position.line as usize - document.len() {
@@ -119,19 +118,24 @@ pub struct MockClient {
 
 impl MockClient {
     pub async fn new() -> Self {
+        Self::with_encoding(PositionEncoding::Utf8).await
+    }
+
+    /// Editor indexing the lines of the document in `encoding`
+    pub async fn with_encoding(encoding: PositionEncoding) -> Self {
         let document = Arc::new(Mutex::new(vec![]));
         let (mainloop, mut server) = async_lsp::MainLoop::new_client(|_server| {
             ServiceBuilder::new()
                 .layer(TracingLayer::default())
                 .layer(CatchUnwindLayer::default())
                 .layer(ConcurrencyLayer::default())
-                .service(ClientState::new_router(document.clone()))
+                .service(ClientState::new_router(document.clone(), encoding))
         });
 
         let mut child = async_process::Command::from(
             Command::cargo_bin("client").expect("client binary to exist"),
         )
-        .arg("ws://127.0.0.1:7575")
+        .arg(super::server::SERVER_ADDR)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::inherit())
@@ -153,6 +157,10 @@ impl MockClient {
                         work_done_progress: Some(true),
                         ..WindowClientCapabilities::default()
                     }),
+                    general: Some(GeneralClientCapabilities {
+                        position_encodings: Some(vec![encoding.kind()]),
+                        ..GeneralClientCapabilities::default()
+                    }),
                     ..ClientCapabilities::default()
                 },
                 ..InitializeParams::default()
@@ -185,7 +193,7 @@ impl MockClient {
         params: DidChangeTextDocumentParams,
     ) -> async_lsp::Result<()> {
         self.server
-            .emit(change_event_to_workspace_edit(&params))
+            .emit(change_event_to_workspace_edit(&params, None).expect("Changes to be ranged"))
             .expect("Can apply local changes");
         self.server.did_change(params)
     }
@@ -194,6 +202,18 @@ impl MockClient {
         self.document.lock().unwrap().join("\n")
     }
 
+    /// Waits for the remote changes to reach the document, returns it as is after a while
+    pub async fn document_eventually(&self, expected: &str) -> String {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        loop {
+            let document = self.document();
+            if document == expected || Instant::now() > deadline {
+                return document;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
     // manual drop because Async drop doesn't exist yet
     pub async fn drop(mut self) {
         let _ = self.server.emit(Stop);
@@ -1,2 +1,4 @@
+#![allow(dead_code)]
 pub mod lsp_client;
 pub mod proptest_structs;
+pub mod server;
@@ -0,0 +1,71 @@
+use std::{process::Command, time::Duration};
+
+use assert_cmd::cargo::CommandCargoExt as _;
+use async_process::Child;
+use codlab::messages::{ClientMessage, ServerMessage};
+use futures::{SinkExt as _, StreamExt as _};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{
+    MaybeTlsStream, WebSocketStream,
+    tungstenite::{self, client::IntoClientRequest as _, http::header::AUTHORIZATION},
+};
+
+pub const SERVER_ADDR: &str = "ws://127.0.0.1:7575";
+
+pub type Connection = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Spawns the server binary and waits until it accepts connections
+pub async fn spawn_server() -> Child {
+    spawn_server_with(&[], SERVER_ADDR).await
+}
+
+/// Spawns the server binary with extra arguments and waits until it accepts connections at `addr`
+pub async fn spawn_server_with(args: &[&str], addr: &str) -> Child {
+    let child =
+        async_process::Command::from(Command::cargo_bin("server").expect("server binary to exist"))
+            .args(args)
+            .kill_on_drop(true)
+            .spawn()
+            .expect("could not spawn server");
+    while tokio_tungstenite::connect_async(addr).await.is_err() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    child
+}
+
+/// Connects to the server without going through the client binary,
+/// `path` selects the session to join
+pub async fn connect(path: &str) -> Connection {
+    connect_with_token(&format!("{SERVER_ADDR}{path}"), None).await
+}
+
+/// Connects to the server at `addr`, giving it `token` in the handshake
+pub async fn connect_with_token(addr: &str, token: Option<&str>) -> Connection {
+    let mut request = addr.into_client_request().unwrap();
+    if let Some(token) = token {
+        request
+            .headers_mut()
+            .insert(AUTHORIZATION, format!("Bearer {token}").parse().unwrap());
+    }
+    let (ws, _) = tokio_tungstenite::connect_async(request)
+        .await
+        .expect("to connect to the server");
+    ws
+}
+
+pub async fn send(ws: &mut Connection, msg: &ClientMessage) {
+    ws.send(tungstenite::Message::Text(
+        serde_json::to_string(msg).unwrap().into(),
+    ))
+    .await
+    .expect("to send a message to the server");
+}
+
+pub async fn recv(ws: &mut Connection) -> ServerMessage {
+    let msg = tokio::time::timeout(Duration::from_secs(1), ws.next())
+        .await
+        .expect("the server to answer in time")
+        .expect("the connection to be open")
+        .expect("to receive a message");
+    serde_json::from_str(msg.to_text().unwrap()).expect("a valid server message")
+}
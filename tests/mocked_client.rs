@@ -1,31 +1,28 @@
 /// Runs the lsp-server (client bin) with a mocked lsp-client
 mod common;
 
-use assert_cmd::cargo::CommandCargoExt as _;
 use async_lsp::lsp_types::{
     DidChangeTextDocumentParams, DidOpenTextDocumentParams, Position, Range,
     TextDocumentContentChangeEvent, TextDocumentItem, Url,
 };
-use codlab::common::init_logger;
-use common::lsp_client;
-use std::{env::temp_dir, process::Command, time::Duration};
+use codlab::{common::init_logger, encoding::PositionEncoding};
+use common::{lsp_client, server::spawn_server};
+use std::env::temp_dir;
 
 #[tokio::test]
 async fn test_mocked_clients() -> anyhow::Result<()> {
     init_logger();
 
-    let mut _server_child =
-        async_process::Command::from(Command::cargo_bin("server").expect("server binary to exist"))
-            .kill_on_drop(true)
-            .spawn()
-            .expect("could not spawn server");
+    let _server_child = spawn_server().await;
 
     let work_dir = temp_dir();
     let mut client1 = lsp_client::MockClient::new().await;
-    let client2 = lsp_client::MockClient::new().await;
+    // counts the positions differently, chars outside of the BMP take two code units
+    let mut client2 = lsp_client::MockClient::with_encoding(PositionEncoding::Utf16).await;
 
     let file_uri = Url::from_file_path(work_dir.join("src/lib.rs")).unwrap();
-    let text = ""; // TODO: send open document to others for initial content
+    // shared with the other clients when opened
+    let text = "🦀hello";
     let added = "test";
     client1.did_open(DidOpenTextDocumentParams {
         text_document: TextDocumentItem {
@@ -40,26 +37,45 @@ async fn test_mocked_clients() -> anyhow::Result<()> {
         .did_change(DidChangeTextDocumentParams {
             text_document: async_lsp::lsp_types::VersionedTextDocumentIdentifier {
                 uri: file_uri.clone(),
-                version: 0,
+                version: 1,
             },
             content_changes: vec![TextDocumentContentChangeEvent {
-                range: Some(Range::new(Position::new(0, 0), Position::new(0, 0))),
+                // after the crab, in bytes
+                range: Some(Range::new(Position::new(0, 4), Position::new(0, 4))),
                 text: added.to_owned(),
                 range_length: None,
             }],
         })
         .await?;
 
-    // this is not great
-    tokio::time::sleep(Duration::from_millis(10)).await;
+    let expected = "🦀testhello";
+    assert_eq!(client1.document_eventually(expected).await, expected);
+    assert_eq!(client2.document_eventually(expected).await, expected);
 
-    // let expected = format!("{}{}", added, text);
-    // assert_eq!(client1.document(), expected);
-    // assert_eq!(client2.document(), expected);
-    assert!(!client1.document().is_empty());
-    assert_eq!(client1.document(), client2.document());
+    client2
+        .did_change(DidChangeTextDocumentParams {
+            text_document: async_lsp::lsp_types::VersionedTextDocumentIdentifier {
+                uri: file_uri.clone(),
+                version: 1,
+            },
+            content_changes: vec![TextDocumentContentChangeEvent {
+                // after "🦀test", in UTF-16 code units
+                range: Some(Range::new(Position::new(0, 6), Position::new(0, 6))),
+                text: "🎉".to_owned(),
+                range_length: None,
+            }],
+        })
+        .await?;
+    let expected = "🦀test🎉hello";
+    assert_eq!(client2.document_eventually(expected).await, expected);
+    assert_eq!(client1.document_eventually(expected).await, expected);
+
+    // a client joining late receives the current content
+    let client3 = lsp_client::MockClient::new().await;
+    assert_eq!(client3.document_eventually(expected).await, expected);
 
     client1.drop().await;
     client2.drop().await;
+    client3.drop().await;
     Ok(())
 }
@@ -38,19 +38,22 @@ async fn test_mocked_clients_quickcheck(params: TestCase) -> anyhow::Result<()>
 
     let file_uri = Url::from_file_path(work_dir.join("src/lib.rs")).unwrap();
 
+    let (mut version1, mut version2) = (0, 0);
     for change in params.changes {
-        match change.from {
-            Client::Client1 => &mut client1,
-            Client::Client2 => &mut client2,
-        }
-        .did_change(DidChangeTextDocumentParams {
-            text_document: async_lsp::lsp_types::VersionedTextDocumentIdentifier {
-                uri: file_uri.clone(),
-                version: 0,
-            },
-            content_changes: change.changes,
-        })
-        .await?;
+        let (client, version) = match change.from {
+            Client::Client1 => (&mut client1, &mut version1),
+            Client::Client2 => (&mut client2, &mut version2),
+        };
+        *version += 1;
+        client
+            .did_change(DidChangeTextDocumentParams {
+                text_document: async_lsp::lsp_types::VersionedTextDocumentIdentifier {
+                    uri: file_uri.clone(),
+                    version: *version,
+                },
+                content_changes: change.changes,
+            })
+            .await?;
     }
 
     // this is not great
@@ -64,7 +67,8 @@ async fn test_mocked_clients_quickcheck(params: TestCase) -> anyhow::Result<()>
     Ok(())
 }
 
-// #[test] // TODO: fix the race condition with CRDTs
+#[test]
+#[ignore = "TODO: fix the race condition with CRDTs"]
 fn test_mocked_clients_quickcheck_sync() -> proptest::test_runner::TestCaseResult {
     let mut _server_child =
         async_process::Command::from(Command::cargo_bin("server").expect("server binary to exist"))
@@ -0,0 +1,620 @@
+/// Talks to the server directly, the way the client binary does
+mod common;
+
+use assert_cmd::cargo::CommandCargoExt as _;
+use async_lsp::lsp_types::{
+    DidChangeTextDocumentParams, Position, Range, TextDocumentContentChangeEvent, Url,
+    VersionedTextDocumentIdentifier,
+};
+use codlab::messages::{
+    Change, Checksum, ClientMessage, CommonMessage, Presence, Role, ServerMessage, SyncEngine,
+};
+use common::server::{
+    Connection, connect, connect_with_token, recv, send, spawn_server, spawn_server_with,
+};
+use futures::{SinkExt as _, StreamExt as _};
+use pretty_assertions::assert_eq;
+use std::{process::Command, time::Duration};
+use tokio_tungstenite::tungstenite;
+use uuid::Uuid;
+
+/// Connects to a session as an anonymous editor and checks the greeting
+async fn join(path: &str) -> Connection {
+    join_as(path, None, Role::Editor).await
+}
+
+/// Connects to a session, checks the greeting and says hello
+async fn join_as(path: &str, username: Option<&str>, role: Role) -> Connection {
+    let mut ws = connect(path).await;
+    assert!(matches!(
+        recv(&mut ws).await,
+        ServerMessage::SyncEngine(SyncEngine::Ot)
+    ));
+    let username = username.map(str::to_owned);
+    send(&mut ws, &ClientMessage::Hello { username, role }).await;
+    ws
+}
+
+/// Connects to the server at `addr`, skips the greeting and says hello as an editor
+async fn join_with_token(addr: &str, token: Option<&str>) -> Connection {
+    let mut ws = connect_with_token(addr, token).await;
+    recv(&mut ws).await;
+    let hello = ClientMessage::Hello {
+        username: None,
+        role: Role::Editor,
+    };
+    send(&mut ws, &hello).await;
+    ws
+}
+
+fn insert(uri: &Url, version: i32, revision: u64, text: &str) -> ClientMessage {
+    ClientMessage::Common(CommonMessage::Change(Change {
+        id: Uuid::new_v4(),
+        author: None,
+        revision,
+        change: DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier::new(uri.clone(), version),
+            content_changes: vec![TextDocumentContentChangeEvent {
+                range: Some(Range::new(Position::new(0, 0), Position::new(0, 0))),
+                range_length: None,
+                text: text.to_owned(),
+            }],
+        },
+    }))
+}
+
+// the scenarios share the server, which always listens on the same port
+#[tokio::test]
+async fn test_server() {
+    let _server_child = spawn_server().await;
+    checksums().await;
+    sessions().await;
+    presence().await;
+    authors().await;
+    observers().await;
+    history().await;
+    opening().await;
+    saving().await;
+    peers().await;
+    authentication().await;
+    storage().await;
+    recording().await;
+}
+
+async fn checksums() {
+    let uri = Url::parse("file:///tmp/checksums.rs").unwrap();
+    let mut ws = join("/").await;
+    send(&mut ws, &insert(&uri, 1, 0, "hello")).await;
+    assert!(matches!(
+        recv(&mut ws).await,
+        ServerMessage::AcknowledgeChange { revision: 1, .. }
+    ));
+
+    let checksum = |revision, text| Checksum {
+        uri: uri.clone(),
+        revision,
+        crdt_heads: None,
+        hash: Checksum::hash(text),
+    };
+    // matching checksums are not answered
+    send(
+        &mut ws,
+        &ClientMessage::Checksums(vec![checksum(1, "hello"), checksum(0, "")]),
+    )
+    .await;
+    send(&mut ws, &ClientMessage::RequestSnapshot).await;
+    assert!(matches!(recv(&mut ws).await, ServerMessage::Snapshot(_)));
+
+    send(
+        &mut ws,
+        &ClientMessage::Checksums(vec![checksum(1, "hallo")]),
+    )
+    .await;
+    match recv(&mut ws).await {
+        ServerMessage::Correction(snapshot) => {
+            assert_eq!(snapshot.uri, uri);
+            assert_eq!(snapshot.revision, 1);
+            assert_eq!(snapshot.text, "hello");
+        }
+        msg => panic!("expected a correction, got {msg:?}"),
+    }
+
+    // a change with an old version is reported as a desync
+    send(&mut ws, &insert(&uri, 1, 1, "again")).await;
+    match recv(&mut ws).await {
+        ServerMessage::Correction(snapshot) => assert_eq!(snapshot.text, "hello"),
+        msg => panic!("expected a correction, got {msg:?}"),
+    }
+
+    // so is a change without content changes
+    let mut empty = insert(&uri, 2, 1, "");
+    if let ClientMessage::Common(CommonMessage::Change(change)) = &mut empty {
+        change.change.content_changes.clear();
+    }
+    send(&mut ws, &empty).await;
+    match recv(&mut ws).await {
+        ServerMessage::Correction(snapshot) => assert_eq!(snapshot.text, "hello"),
+        msg => panic!("expected a correction, got {msg:?}"),
+    }
+}
+
+async fn sessions() {
+    let uri = Url::parse("file:///tmp/sessions.rs").unwrap();
+    let mut a1 = join("/team-a").await;
+    let mut a2 = join("/team-a/").await;
+    let mut b = join("/team-b").await;
+
+    send(&mut a1, &insert(&uri, 1, 0, "a")).await;
+    assert!(matches!(
+        recv(&mut a1).await,
+        ServerMessage::AcknowledgeChange { revision: 1, .. }
+    ));
+    assert!(matches!(
+        recv(&mut a2).await,
+        ServerMessage::Common(CommonMessage::Change(Change { revision: 1, .. }))
+    ));
+    // the other session has its own documents
+    send(&mut b, &ClientMessage::RequestSnapshot).await;
+    match recv(&mut b).await {
+        ServerMessage::Snapshot(snapshots) => assert!(snapshots.is_empty()),
+        msg => panic!("expected a snapshot, got {msg:?}"),
+    }
+
+    send(&mut b, &ClientMessage::JoinSession("team-a".to_owned())).await;
+    match recv(&mut b).await {
+        ServerMessage::Joined(session) => assert_eq!(session, "team-a"),
+        msg => panic!("expected to join the session, got {msg:?}"),
+    }
+    match recv(&mut b).await {
+        ServerMessage::Snapshot(snapshots) => {
+            assert_eq!(snapshots.len(), 1);
+            assert_eq!(snapshots[0].text, "a");
+        }
+        msg => panic!("expected a snapshot, got {msg:?}"),
+    }
+    send(&mut b, &insert(&uri, 1, 1, "b")).await;
+    assert!(matches!(
+        recv(&mut a1).await,
+        ServerMessage::Common(CommonMessage::Change(Change { revision: 2, .. }))
+    ));
+}
+
+async fn presence() {
+    let uri = Url::parse("file:///tmp/presence.rs").unwrap();
+    let mut a = join("/presence").await;
+    let mut b = join("/presence").await;
+
+    send(&mut a, &insert(&uri, 1, 0, "world")).await;
+    assert!(matches!(
+        recv(&mut a).await,
+        ServerMessage::AcknowledgeChange { revision: 1, .. }
+    ));
+    assert!(matches!(
+        recv(&mut b).await,
+        ServerMessage::Common(CommonMessage::Change(Change { revision: 1, .. }))
+    ));
+    send(&mut a, &insert(&uri, 2, 1, "hello ")).await;
+    assert!(matches!(
+        recv(&mut a).await,
+        ServerMessage::AcknowledgeChange { revision: 2, .. }
+    ));
+
+    // b did not receive the second change yet when moving its cursor after "wor"
+    let user = Uuid::new_v4();
+    let presence = Presence {
+        user,
+        username: None,
+        uri: uri.clone(),
+        revision: 1,
+        cursor: Position::new(0, 3),
+        selections: vec![Range::new(Position::new(0, 0), Position::new(0, 3))],
+    };
+    send(&mut b, &ClientMessage::Presence(presence.clone())).await;
+    let rebased = Presence {
+        revision: 2,
+        cursor: Position::new(0, 9),
+        selections: vec![Range::new(Position::new(0, 6), Position::new(0, 9))],
+        ..presence
+    };
+    match recv(&mut a).await {
+        ServerMessage::Presence(presence) => assert_eq!(presence, rebased),
+        msg => panic!("expected a presence, got {msg:?}"),
+    }
+
+    // a client joining late receives the cursors of its peers
+    let mut c = join("/").await;
+    send(&mut c, &ClientMessage::JoinSession("presence".to_owned())).await;
+    assert!(matches!(recv(&mut c).await, ServerMessage::Joined(_)));
+    assert!(matches!(recv(&mut c).await, ServerMessage::Snapshot(_)));
+    match recv(&mut c).await {
+        ServerMessage::Presence(presence) => assert_eq!(presence, rebased),
+        msg => panic!("expected a presence, got {msg:?}"),
+    }
+
+    drop(b);
+    match recv(&mut a).await {
+        ServerMessage::UserLeft(left) => assert_eq!(left, user),
+        msg => panic!("expected the user to leave, got {msg:?}"),
+    }
+}
+
+async fn authors() {
+    let uri = Url::parse("file:///tmp/authors.rs").unwrap();
+    let mut a = join_as("/authors", Some("alice"), Role::Editor).await;
+    let mut b = join("/authors").await;
+
+    send(&mut a, &insert(&uri, 1, 0, "hello")).await;
+    match recv(&mut b).await {
+        ServerMessage::Common(CommonMessage::Change(change)) => {
+            assert_eq!(change.author.as_deref(), Some("alice"));
+        }
+        msg => panic!("expected a change, got {msg:?}"),
+    }
+
+    // the server names the peers, so that they can be followed
+    send(
+        &mut a,
+        &ClientMessage::Presence(Presence {
+            user: Uuid::new_v4(),
+            username: Some("mallory".to_owned()),
+            uri: uri.clone(),
+            revision: 1,
+            cursor: Position::new(0, 5),
+            selections: vec![],
+        }),
+    )
+    .await;
+    match recv(&mut b).await {
+        ServerMessage::Presence(presence) => {
+            assert_eq!(presence.username.as_deref(), Some("alice"));
+        }
+        msg => panic!("expected a presence, got {msg:?}"),
+    }
+}
+
+async fn observers() {
+    let uri = Url::parse("file:///tmp/observers.rs").unwrap();
+    let mut editor = join("/observers").await;
+    let mut observer = join_as("/observers", None, Role::Observer).await;
+    // the role can't change once chosen
+    send(
+        &mut observer,
+        &ClientMessage::Hello {
+            username: None,
+            role: Role::Editor,
+        },
+    )
+    .await;
+
+    // so is a client which did not say hello
+    let mut silent = connect("/observers").await;
+    recv(&mut silent).await;
+
+    send(&mut observer, &insert(&uri, 1, 0, "dropped")).await;
+    send(&mut silent, &insert(&uri, 1, 0, "dropped")).await;
+    send(&mut editor, &insert(&uri, 1, 0, "kept")).await;
+    // the changes of the observers were not applied
+    assert!(matches!(
+        recv(&mut editor).await,
+        ServerMessage::AcknowledgeChange { revision: 1, .. }
+    ));
+    match recv(&mut observer).await {
+        ServerMessage::Common(CommonMessage::Change(change)) => {
+            assert_eq!(change.change.content_changes[0].text, "kept");
+        }
+        msg => panic!("expected a change, got {msg:?}"),
+    }
+}
+
+async fn history() {
+    let uri = Url::parse("file:///tmp/history.rs").unwrap();
+    let mut ws = join_as("/history", Some("alice"), Role::Editor).await;
+    send(&mut ws, &insert(&uri, 1, 0, "world")).await;
+    recv(&mut ws).await;
+    send(&mut ws, &insert(&uri, 2, 1, "hello ")).await;
+    recv(&mut ws).await;
+
+    let mut history = async |range| {
+        send(
+            &mut ws,
+            &ClientMessage::RequestHistory {
+                uri: uri.clone(),
+                range,
+            },
+        )
+        .await;
+        match recv(&mut ws).await {
+            ServerMessage::History(history) => history
+                .edits
+                .into_iter()
+                .map(|edit| {
+                    assert_eq!(edit.author.as_deref(), Some("alice"));
+                    (edit.revision, edit.changes[0].text.clone())
+                })
+                .collect::<Vec<_>>(),
+            msg => panic!("expected the history, got {msg:?}"),
+        }
+    };
+    assert_eq!(
+        history(None).await,
+        vec![(1, "world".to_owned()), (2, "hello ".to_owned())]
+    );
+    assert_eq!(
+        history(Some(Range::new(Position::new(0, 6), Position::new(0, 11)))).await,
+        vec![(1, "world".to_owned())]
+    );
+}
+
+async fn opening() {
+    let uri = Url::parse("file:///tmp/opening.rs").unwrap();
+    let mut a = join("/opening").await;
+    let mut b = join("/opening").await;
+    let open = |text| match insert(&uri, 0, 0, text) {
+        ClientMessage::Common(change) => ClientMessage::OpenDocument {
+            uri: uri.clone(),
+            content: Some(change),
+        },
+        _ => unreachable!(),
+    };
+
+    send(&mut a, &open("hello")).await;
+    match recv(&mut b).await {
+        ServerMessage::DocumentOpened { snapshot, .. } => {
+            assert_eq!(snapshot.uri, uri);
+            assert_eq!(snapshot.text, "hello");
+            assert_eq!(snapshot.revision, 1);
+        }
+        msg => panic!("expected the document to be shared, got {msg:?}"),
+    }
+    assert!(matches!(
+        recv(&mut a).await,
+        ServerMessage::AcknowledgeChange { revision: 1, .. }
+    ));
+
+    // already shared, the content of the session wins
+    send(&mut b, &open("bonjour")).await;
+    match recv(&mut b).await {
+        ServerMessage::Correction(snapshot) => assert_eq!(snapshot.text, "hello"),
+        msg => panic!("expected a correction, got {msg:?}"),
+    }
+
+    // editors count the versions again once they reopen a document
+    send(&mut a, &insert(&uri, 5, 1, "oh ")).await;
+    assert!(matches!(
+        recv(&mut a).await,
+        ServerMessage::AcknowledgeChange { revision: 2, .. }
+    ));
+    send(&mut a, &ClientMessage::CloseDocument(uri.clone())).await;
+    send(
+        &mut a,
+        &ClientMessage::OpenDocument {
+            uri: uri.clone(),
+            content: None,
+        },
+    )
+    .await;
+    send(&mut a, &insert(&uri, 1, 2, "well ")).await;
+    assert!(matches!(
+        recv(&mut a).await,
+        ServerMessage::AcknowledgeChange { revision: 3, .. }
+    ));
+}
+
+async fn saving() {
+    let uri = Url::parse("file:///tmp/saving.rs").unwrap();
+    let mut a = join("/saving").await;
+    let mut b = join("/saving").await;
+
+    send(&mut a, &insert(&uri, 1, 0, "hello")).await;
+    assert!(matches!(
+        recv(&mut a).await,
+        ServerMessage::AcknowledgeChange { revision: 1, .. }
+    ));
+    assert!(matches!(recv(&mut b).await, ServerMessage::Common(_)));
+    // known already, nothing to share
+    send(
+        &mut b,
+        &ClientMessage::OpenDocument {
+            uri: uri.clone(),
+            content: None,
+        },
+    )
+    .await;
+    send(&mut b, &ClientMessage::CloseDocument(uri.clone())).await;
+
+    send(&mut a, &ClientMessage::SaveDocument(uri.clone())).await;
+    match recv(&mut b).await {
+        ServerMessage::DocumentSaved(saved) => assert_eq!(saved.uri, uri),
+        msg => panic!("expected the document to be saved, got {msg:?}"),
+    }
+}
+
+async fn peers() {
+    let uri = Url::parse("file:///tmp/peers.rs").unwrap();
+    let mut a = join("/peers").await;
+    let mut b = join_as("/peers", Some("bob"), Role::Observer).await;
+    send(
+        &mut b,
+        &ClientMessage::OpenDocument {
+            uri: uri.clone(),
+            content: None,
+        },
+    )
+    .await;
+    // the messages of b are handled once it received the answer
+    let list_peers = async |ws: &mut Connection| {
+        send(ws, &ClientMessage::ListPeers).await;
+        match recv(ws).await {
+            ServerMessage::Peers(peers) => peers,
+            msg => panic!("expected the peers, got {msg:?}"),
+        }
+    };
+    list_peers(&mut b).await;
+
+    let peers = list_peers(&mut a).await;
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].name.as_deref(), Some("bob"));
+    assert_eq!(peers[0].role, Role::Observer);
+    assert_eq!(peers[0].open, vec![uri.clone()]);
+
+    send(&mut b, &ClientMessage::CloseDocument(uri)).await;
+    list_peers(&mut b).await;
+    assert!(list_peers(&mut a).await[0].open.is_empty());
+
+    // unexpected frames are ignored, the client stays connected
+    b.send(tungstenite::Message::Binary(vec![1, 2].into()))
+        .await
+        .unwrap();
+    b.send(tungstenite::Message::Text("{".into()))
+        .await
+        .unwrap();
+    list_peers(&mut b).await;
+    assert_eq!(list_peers(&mut a).await.len(), 1);
+}
+
+async fn authentication() {
+    const ADDR: &str = "ws://127.0.0.1:7576";
+    let config = std::env::temp_dir().join(format!("codlab-config-{}.toml", Uuid::new_v4()));
+    std::fs::write(
+        &config,
+        "token = \"secret\"\n[users]\nalice = \"wonderland\"\n",
+    )
+    .unwrap();
+    let _server_child = spawn_server_with(
+        &["--port", "7576", "--config", config.to_str().unwrap()],
+        ADDR,
+    )
+    .await;
+
+    for (token, reason) in [
+        (None, "a token is required to join this server"),
+        (Some("guess"), "invalid token"),
+    ] {
+        let mut ws = connect_with_token(ADDR, token).await;
+        match ws.next().await {
+            Some(Ok(tungstenite::Message::Close(Some(frame)))) => {
+                assert_eq!(frame.reason.as_str(), reason);
+            }
+            msg => panic!("expected the connection to be closed, got {msg:?}"),
+        }
+    }
+
+    let mut ws = connect_with_token(ADDR, Some("secret")).await;
+    assert!(matches!(
+        recv(&mut ws).await,
+        ServerMessage::SyncEngine(SyncEngine::Ot)
+    ));
+
+    // the name of a user is only given to the clients with its token
+    let uri = Url::parse("file:///tmp/authentication.rs").unwrap();
+    let mut alice = join_with_token(ADDR, Some("wonderland")).await;
+    let hello = ClientMessage::Hello {
+        username: Some("alice".to_owned()),
+        role: Role::Editor,
+    };
+    send(&mut ws, &hello).await;
+    send(&mut ws, &insert(&uri, 1, 0, "mallory")).await;
+    assert!(matches!(
+        recv(&mut ws).await,
+        ServerMessage::AcknowledgeChange { revision: 1, .. }
+    ));
+    match recv(&mut alice).await {
+        ServerMessage::Common(CommonMessage::Change(change)) => assert_eq!(change.author, None),
+        msg => panic!("expected a change, got {msg:?}"),
+    }
+    send(&mut alice, &insert(&uri, 1, 1, "alice")).await;
+    match recv(&mut ws).await {
+        ServerMessage::Common(CommonMessage::Change(change)) => {
+            assert_eq!(change.author.as_deref(), Some("alice"));
+        }
+        msg => panic!("expected a change, got {msg:?}"),
+    }
+    std::fs::remove_file(config).unwrap();
+}
+
+async fn storage() {
+    const ADDR: &str = "ws://127.0.0.1:7577";
+    let dir = std::env::temp_dir().join(format!("codlab-sessions-{}", Uuid::new_v4()));
+    let args = ["--port", "7577", "--storage", dir.to_str().unwrap()];
+    let uri = Url::parse("file:///tmp/storage.rs").unwrap();
+
+    let mut server = spawn_server_with(&args, ADDR).await;
+    let mut ws = join_with_token(&format!("{ADDR}/saved"), None).await;
+    send(&mut ws, &insert(&uri, 1, 0, "world")).await;
+    recv(&mut ws).await;
+    send(&mut ws, &insert(&uri, 2, 1, "hello ")).await;
+    recv(&mut ws).await;
+    // the edits are written in the background
+    let log = dir.join("saved").join("log.jsonl");
+    while std::fs::read_to_string(&log).map_or(0, |log| log.lines().count()) < 2 {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    server.kill().unwrap();
+    server.status().await.unwrap();
+
+    let _server_child = spawn_server_with(&args, ADDR).await;
+    let mut ws = join_with_token(&format!("{ADDR}/saved"), None).await;
+    send(&mut ws, &ClientMessage::RequestSnapshot).await;
+    match recv(&mut ws).await {
+        ServerMessage::Snapshot(snapshots) => {
+            assert_eq!(snapshots.len(), 1);
+            assert_eq!(snapshots[0].text, "hello world");
+            assert_eq!(snapshots[0].revision, 2);
+        }
+        msg => panic!("expected a snapshot, got {msg:?}"),
+    }
+    send(
+        &mut ws,
+        &ClientMessage::RequestHistory {
+            uri: uri.clone(),
+            range: None,
+        },
+    )
+    .await;
+    assert!(matches!(
+        recv(&mut ws).await,
+        ServerMessage::History(history) if history.edits.len() == 2
+    ));
+    send(&mut ws, &insert(&uri, 3, 2, "oh ")).await;
+    assert!(matches!(
+        recv(&mut ws).await,
+        ServerMessage::AcknowledgeChange { revision: 3, .. }
+    ));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+async fn recording() {
+    const ADDR: &str = "ws://127.0.0.1:7578";
+    let path = std::env::temp_dir().join(format!("codlab-recording-{}.jsonl", Uuid::new_v4()));
+    let _server_child = spawn_server_with(
+        &["--port", "7578", "--record", path.to_str().unwrap()],
+        ADDR,
+    )
+    .await;
+    let uri = Url::parse("file:///tmp/recording.rs").unwrap();
+
+    let mut ws = join_with_token(&format!("{ADDR}/recorded"), None).await;
+    send(&mut ws, &insert(&uri, 1, 0, "world")).await;
+    recv(&mut ws).await;
+    send(&mut ws, &insert(&uri, 2, 1, "hello ")).await;
+    recv(&mut ws).await;
+    // the changes are written in the background
+    while std::fs::read_to_string(&path).map_or(0, |recording| recording.lines().count()) < 2 {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    let replay = |revision: &str| {
+        let output = Command::cargo_bin("codlab-replay")
+            .unwrap()
+            .arg(&path)
+            .args(["--session", "recorded", "show", uri.as_str()])
+            .args(["--revision", revision])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{output:?}");
+        String::from_utf8(output.stdout).unwrap()
+    };
+    assert_eq!(replay("1"), "world");
+    assert_eq!(replay("2"), "hello world");
+    std::fs::remove_file(path).unwrap();
+}
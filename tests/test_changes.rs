@@ -141,6 +141,50 @@ fn whatever_versioned_text_document() -> VersionedTextDocumentIdentifier {
             },
         ],
     )]
+#[case(
+        ChangeEvent {
+            change: DidChangeTextDocumentParams {
+                text_document: whatever_versioned_text_document(),
+                content_changes: vec![async_lsp::lsp_types::TextDocumentContentChangeEvent {
+                    range: Some(async_lsp::lsp_types::Range {
+                        start: async_lsp::lsp_types::Position::new(0, 0),
+                        end: async_lsp::lsp_types::Position::new(0, 0),
+                    }),
+                    text: "😀b".to_owned(),
+                    range_length: None,
+                }],
+            },
+        },
+        vec![
+            ChangeEvent {
+                change: DidChangeTextDocumentParams {
+                    text_document: whatever_versioned_text_document(),
+                    content_changes: vec![async_lsp::lsp_types::TextDocumentContentChangeEvent {
+                        range: Some(async_lsp::lsp_types::Range {
+                            start: async_lsp::lsp_types::Position::new(0, 0),
+                            end: async_lsp::lsp_types::Position::new(0, 0),
+                        }),
+                        text: "😀".to_owned(),
+                        range_length: None,
+                    }],
+                },
+            },
+            // positions are counted in chars between the clients and the server
+            ChangeEvent {
+                change: DidChangeTextDocumentParams {
+                    text_document: whatever_versioned_text_document(),
+                    content_changes: vec![async_lsp::lsp_types::TextDocumentContentChangeEvent {
+                        range: Some(async_lsp::lsp_types::Range {
+                            start: async_lsp::lsp_types::Position::new(0, 1),
+                            end: async_lsp::lsp_types::Position::new(0, 1),
+                        }),
+                        text: "b".to_owned(),
+                        range_length: None,
+                    }],
+                },
+            },
+        ],
+    )]
 fn test_change_split_into_units(
     #[case] change: ChangeEvent,
     #[case] expected_units: Vec<ChangeEvent>,
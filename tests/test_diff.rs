@@ -0,0 +1,80 @@
+use async_lsp::lsp_types::TextEdit;
+use codlab::{buffer::Buffer, diff};
+use proptest::prelude::*;
+
+/// Few different chars, so that the texts have a lot in common, including multi-byte ones
+const TEXT_REGEX: &str = "[ab \né😀]{0,40}";
+
+/// Applies edits all at once, as in a `WorkspaceEdit`: their ranges refer to the original text
+fn apply_at_once(text: &str, edits: &[TextEdit]) -> String {
+    let buffer = Buffer::new(text);
+    let mut offsets: Vec<_> = edits
+        .iter()
+        .map(|edit| {
+            (
+                buffer.position_to_char(edit.range.start),
+                buffer.position_to_char(edit.range.end),
+                edit.new_text.as_str(),
+            )
+        })
+        .collect();
+    offsets.sort_by_key(|(start, _, _)| *start);
+    let chars: Vec<_> = text.chars().collect();
+    let mut result = String::new();
+    let mut offset = 0;
+    for (start, end, new_text) in offsets {
+        assert!(start >= offset, "edits overlap: {edits:?}");
+        result.extend(&chars[offset..start]);
+        result.push_str(new_text);
+        offset = end;
+    }
+    result.extend(&chars[offset..]);
+    result
+}
+
+/// Number of chars to delete and insert to turn `old` into `new`, with the longest common
+/// subsequence
+fn edit_distance(old: &str, new: &str) -> usize {
+    let old: Vec<_> = old.chars().collect();
+    let new: Vec<_> = new.chars().collect();
+    let mut lcs = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in 0..old.len() {
+        for j in 0..new.len() {
+            lcs[i + 1][j + 1] = if old[i] == new[j] {
+                lcs[i][j] + 1
+            } else {
+                lcs[i][j + 1].max(lcs[i + 1][j])
+            };
+        }
+    }
+    old.len() + new.len() - 2 * lcs[old.len()][new.len()]
+}
+
+proptest! {
+    #[test]
+    fn test_edits_apply_at_once(old in TEXT_REGEX, new in TEXT_REGEX) {
+        let edits = diff::text_edits(&old, &new);
+        prop_assert_eq!(apply_at_once(&old, &edits), new);
+    }
+
+    #[test]
+    fn test_changes_apply_one_after_the_other(old in TEXT_REGEX, new in TEXT_REGEX) {
+        let mut buffer = Buffer::new(&old);
+        buffer.apply_changes(&diff::changes(&old, &new));
+        prop_assert_eq!(buffer.to_string(), new);
+    }
+
+    #[test]
+    fn test_edits_are_minimal(old in TEXT_REGEX, new in TEXT_REGEX) {
+        let buffer = Buffer::new(&old);
+        let changed: usize = diff::text_edits(&old, &new)
+            .iter()
+            .map(|edit| {
+                let deleted = buffer.position_to_char(edit.range.end)
+                    - buffer.position_to_char(edit.range.start);
+                deleted + edit.new_text.chars().count()
+            })
+            .sum();
+        prop_assert_eq!(changed, edit_distance(&old, &new));
+    }
+}